@@ -0,0 +1,37 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    /// A file is not in the expected format.
+    InvalidFormat(&'static str),
+    /// A file's format version is not supported.
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "{}", e),
+            FormatError::InvalidFormat(msg) => write!(f, "invalid file: {}", msg),
+            FormatError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
@@ -0,0 +1,420 @@
+//! The `.h3tess` file format.
+//!
+//! ```text
+//! magic          8 bytes  "H3TESS\0\0"
+//! version        u16
+//! resolution     u8
+//! value type     u8
+//! record count   u64
+//! aggregation    u8       since version 2
+//! source count   u32
+//! sources        source count * (name, u32 CRC-32)
+//! tool           string
+//! records        record count * (u64 H3 index, value)
+//! ```
+//!
+//! All integers are little endian and strings are a `u16` byte length
+//! followed by UTF-8. Version 1 files are read as
+//! [`Aggregation::Sum`]. Files written before the header was introduced
+//! are a bare sequence of `(u64, f32)` records and can be read with
+//! [`H3TessReader::legacy`].
+
+use crate::error::FormatError;
+use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub const MAGIC: [u8; 8] = *b"H3TESS\0\0";
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Oldest format version which can still be read.
+const MIN_FORMAT_VERSION: u16 = 1;
+
+/// Byte offset of the record count from the start of the header.
+const RECORD_COUNT_OFFSET: u64 = 12;
+
+/// Size in bytes of a single (u64, f32) record.
+pub const RECORD_SIZE: u64 = 12;
+
+/// Type of the value stored with each cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    F32,
+}
+
+impl ValueType {
+    fn to_u8(self) -> u8 {
+        match self {
+            ValueType::F32 => 0,
+        }
+    }
+
+    fn from_u8(val: u8) -> Result<Self, FormatError> {
+        match val {
+            0 => Ok(ValueType::F32),
+            _ => Err(FormatError::InvalidFormat("unknown value type")),
+        }
+    }
+}
+
+/// How the values of child cells were combined into a coarser cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Aggregation {
+    /// Sum of the children, for counts such as population.
+    Sum,
+    /// Area-weighted mean of the children, for densities.
+    Mean,
+    /// Smallest child value.
+    Min,
+    /// Largest child value.
+    Max,
+    /// Number of finest resolution cells with a value.
+    Count,
+}
+
+impl Aggregation {
+    fn to_u8(self) -> u8 {
+        match self {
+            Aggregation::Sum => 0,
+            Aggregation::Mean => 1,
+            Aggregation::Min => 2,
+            Aggregation::Max => 3,
+            Aggregation::Count => 4,
+        }
+    }
+
+    fn from_u8(val: u8) -> Result<Self, FormatError> {
+        match val {
+            0 => Ok(Aggregation::Sum),
+            1 => Ok(Aggregation::Mean),
+            2 => Ok(Aggregation::Min),
+            3 => Ok(Aggregation::Max),
+            4 => Ok(Aggregation::Count),
+            _ => Err(FormatError::InvalidFormat("unknown aggregation")),
+        }
+    }
+}
+
+/// A raster a file's values were derived from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceInfo {
+    pub name: String,
+    /// CRC-32 of the uncompressed source.
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct H3TessHeader {
+    pub version: u16,
+    /// H3 resolution the values were tessellated or aggregated at.
+    pub resolution: u8,
+    pub value_type: ValueType,
+    pub record_count: u64,
+    pub aggregation: Aggregation,
+    pub sources: Vec<SourceInfo>,
+    /// Name and version of the tool which wrote the file.
+    pub tool: String,
+}
+
+impl H3TessHeader {
+    /// Returns a header for a new file written by `tool`.
+    pub fn new(tool: &str, resolution: u8, sources: Vec<SourceInfo>) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            resolution,
+            value_type: ValueType::F32,
+            record_count: 0,
+            aggregation: Aggregation::Sum,
+            sources,
+            tool: tool.to_string(),
+        }
+    }
+
+    /// Sets how values were combined into coarser cells.
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    pub fn write<W: Write>(&self, wtr: &mut W) -> Result<(), FormatError> {
+        wtr.write_all(&MAGIC)?;
+        wtr.write_u16::<LE>(self.version)?;
+        wtr.write_u8(self.resolution)?;
+        wtr.write_u8(self.value_type.to_u8())?;
+        wtr.write_u64::<LE>(self.record_count)?;
+        if self.version >= 2 {
+            wtr.write_u8(self.aggregation.to_u8())?;
+        }
+        wtr.write_u32::<LE>(self.sources.len() as u32)?;
+        for source in &self.sources {
+            write_string(wtr, &source.name)?;
+            wtr.write_u32::<LE>(source.checksum)?;
+        }
+        write_string(wtr, &self.tool)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(rdr: &mut R) -> Result<Self, FormatError> {
+        let mut magic = [0; 8];
+        rdr.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(FormatError::InvalidFormat("not an h3tess file"));
+        }
+        let version = rdr.read_u16::<LE>()?;
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let resolution = rdr.read_u8()?;
+        let value_type = ValueType::from_u8(rdr.read_u8()?)?;
+        let record_count = rdr.read_u64::<LE>()?;
+        let aggregation = if version >= 2 {
+            Aggregation::from_u8(rdr.read_u8()?)?
+        } else {
+            Aggregation::Sum
+        };
+        let source_count = rdr.read_u32::<LE>()?;
+        let mut sources = Vec::new();
+        for _ in 0..source_count {
+            let name = read_string(rdr)?;
+            let checksum = rdr.read_u32::<LE>()?;
+            sources.push(SourceInfo { name, checksum });
+        }
+        let tool = read_string(rdr)?;
+        Ok(Self {
+            version,
+            resolution,
+            value_type,
+            record_count,
+            aggregation,
+            sources,
+            tool,
+        })
+    }
+
+    /// Size in bytes of the encoded header.
+    pub fn encoded_len(&self) -> u64 {
+        let sources: usize = self.sources.iter().map(|s| 2 + s.name.len() + 4).sum();
+        let aggregation = usize::from(self.version >= 2);
+        (RECORD_COUNT_OFFSET as usize + 8 + aggregation + 4 + sources + 2 + self.tool.len()) as u64
+    }
+}
+
+fn write_string<W: Write>(wtr: &mut W, s: &str) -> Result<(), FormatError> {
+    let len = u16::try_from(s.len()).map_err(|_| FormatError::InvalidFormat("string too long"))?;
+    wtr.write_u16::<LE>(len)?;
+    wtr.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_string<R: Read>(rdr: &mut R) -> Result<String, FormatError> {
+    let len = rdr.read_u16::<LE>()?;
+    let mut buf = vec![0; len as usize];
+    rdr.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| FormatError::InvalidFormat("invalid UTF-8 string"))
+}
+
+/// Writes a header followed by records, filling in the record count
+/// when finished.
+pub struct H3TessWriter<W: Write + Seek> {
+    wtr: W,
+    start: u64,
+    header: H3TessHeader,
+}
+
+impl<W: Write + Seek> H3TessWriter<W> {
+    pub fn new(mut wtr: W, header: H3TessHeader) -> Result<Self, FormatError> {
+        let start = wtr.stream_position()?;
+        header.write(&mut wtr)?;
+        Ok(Self { wtr, start, header })
+    }
+
+    pub fn write(&mut self, h3_index: u64, val: f32) -> Result<(), FormatError> {
+        self.wtr.write_u64::<LE>(h3_index)?;
+        self.wtr.write_f32::<LE>(val)?;
+        self.header.record_count += 1;
+        Ok(())
+    }
+
+    /// Sets the checksum of the source at `idx`, for sources only
+    /// checksummed once read. It is written by [`H3TessWriter::finish`].
+    pub fn set_checksum(&mut self, idx: usize, checksum: u32) {
+        self.header.sources[idx].checksum = checksum;
+    }
+
+    /// Rewrites the header with the final record count and checksums,
+    /// and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, FormatError> {
+        let end = self.wtr.stream_position()?;
+        self.wtr.seek(SeekFrom::Start(self.start))?;
+        self.header.write(&mut self.wtr)?;
+        self.wtr.seek(SeekFrom::Start(end))?;
+        self.wtr.flush()?;
+        Ok(self.wtr)
+    }
+}
+
+/// Iterates over the (H3 index, value) records of an `.h3tess` file.
+pub struct H3TessReader<R: Read> {
+    rdr: R,
+    header: Option<H3TessHeader>,
+    /// Records left to read, if known.
+    remaining: Option<u64>,
+    done: bool,
+}
+
+impl<R: Read> H3TessReader<R> {
+    /// Reads and validates the header at the start of `rdr`.
+    pub fn new(mut rdr: R) -> Result<Self, FormatError> {
+        let header = H3TessHeader::read(&mut rdr)?;
+        Ok(Self {
+            rdr,
+            remaining: Some(header.record_count),
+            header: Some(header),
+            done: false,
+        })
+    }
+
+    /// Reads headerless records written before the format was
+    /// versioned.
+    pub fn legacy(rdr: R) -> Self {
+        Self {
+            rdr,
+            header: None,
+            remaining: None,
+            done: false,
+        }
+    }
+
+    /// The file's header, `None` for legacy files.
+    pub fn header(&self) -> Option<&H3TessHeader> {
+        self.header.as_ref()
+    }
+
+    /// Reads one record, returning `None` at a clean end of file.
+    fn read_record(&mut self) -> Result<Option<(u64, f32)>, FormatError> {
+        let mut buf = [0; RECORD_SIZE as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.rdr.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match filled {
+            0 => Ok(None),
+            n if n == buf.len() => {
+                let mut record = &buf[..];
+                Ok(Some((record.read_u64::<LE>()?, record.read_f32::<LE>()?)))
+            }
+            _ => Err(FormatError::InvalidFormat("truncated record")),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(u64, f32)>, FormatError> {
+        match self.remaining {
+            Some(0) => match self.read_record()? {
+                None => Ok(None),
+                Some(_) => Err(FormatError::InvalidFormat("data after last record")),
+            },
+            Some(remaining) => match self.read_record()? {
+                Some(record) => {
+                    self.remaining = Some(remaining - 1);
+                    Ok(Some(record))
+                }
+                None => Err(FormatError::InvalidFormat(
+                    "fewer records than header count",
+                )),
+            },
+            None => self.read_record(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for H3TessReader<R> {
+    type Item = Result<(u64, f32), FormatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.next_record();
+        self.done = !matches!(record, Ok(Some(_)));
+        record.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write_file(records: &[(u64, f32)]) -> Vec<u8> {
+        let header = H3TessHeader::new(
+            "gpwformat test",
+            10,
+            vec![SourceInfo {
+                name: "gpw_v4_population_count_rev11_2020_30_sec_1.asc".to_string(),
+                checksum: 0,
+            }],
+        );
+        let mut wtr = H3TessWriter::new(Cursor::new(Vec::new()), header).unwrap();
+        for (h3_index, val) in records {
+            wtr.write(*h3_index, *val).unwrap();
+        }
+        wtr.set_checksum(0, 0xdeadbeef);
+        wtr.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let records = vec![(0x8a2a1072b59ffff, 1.5), (0x8a2a1072b5bffff, 2.5)];
+        let buf = write_file(&records);
+        let rdr = H3TessReader::new(Cursor::new(&buf)).unwrap();
+        let header = rdr.header().unwrap().clone();
+        assert_eq!(header.record_count, 2);
+        assert_eq!(header.sources[0].checksum, 0xdeadbeef);
+        assert_eq!(header.resolution, 10);
+        assert_eq!(header.encoded_len() + 2 * RECORD_SIZE, buf.len() as u64);
+        assert_eq!(rdr.collect::<Result<Vec<_>, _>>().unwrap(), records);
+
+        let legacy = &buf[header.encoded_len() as usize..];
+        let rdr = H3TessReader::legacy(Cursor::new(legacy));
+        assert_eq!(rdr.collect::<Result<Vec<_>, _>>().unwrap(), records);
+    }
+
+    #[test]
+    fn test_header_versions() {
+        let header =
+            H3TessHeader::new("gpwformat test", 8, Vec::new()).with_aggregation(Aggregation::Mean);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.len() as u64, header.encoded_len());
+        assert_eq!(H3TessHeader::read(&mut &buf[..]).unwrap(), header);
+
+        // Version 1 headers have no aggregation and hold sums.
+        let v1 = H3TessHeader {
+            version: 1,
+            aggregation: Aggregation::Sum,
+            ..header
+        };
+        let mut buf = Vec::new();
+        v1.write(&mut buf).unwrap();
+        assert_eq!(buf.len() as u64, v1.encoded_len());
+        assert_eq!(H3TessHeader::read(&mut &buf[..]).unwrap(), v1);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let buf = write_file(&[(0x8a2a1072b59ffff, 1.5), (0x8a2a1072b5bffff, 2.5)]);
+        let read = |buf: &[u8]| {
+            H3TessReader::new(Cursor::new(buf)).and_then(|rdr| rdr.collect::<Result<Vec<_>, _>>())
+        };
+        assert!(read(&buf).is_ok());
+        assert!(read(&buf[..buf.len() - 1]).is_err());
+        assert!(read(&buf[..buf.len() - RECORD_SIZE as usize]).is_err());
+        assert!(read(&[&buf[..], &[0u8; 12][..]].concat()).is_err());
+        assert!(read(&buf[1..]).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+//! On-disk formats shared by gpwgen and gpws.
+
+pub mod error;
+pub mod h3tess;
+
+pub use error::FormatError;
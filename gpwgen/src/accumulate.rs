@@ -0,0 +1,308 @@
+use crate::error::GpwError;
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+/// Default number of distinct cells held in memory before spilling a
+/// sorted run to disk.
+pub const DEFAULT_MAX_CELLS: usize = 1 << 24;
+
+/// Approximate memory used per in-memory cell, including hash map
+/// overhead and the sorted copy made when spilling.
+const BYTES_PER_CELL: u64 = 48;
+
+/// Maximum number of runs open at once when merging. More runs are
+/// merged in passes, so the open file limit isn't reached.
+const MAX_MERGE_RUNS: usize = 256;
+
+/// Sums values per H3 cell and yields each cell once, sorted by H3
+/// index. Another merge such as `f64::max` can be set with
+/// [`CellAccumulator::with_merge`].
+///
+/// When more than `max_cells` distinct cells are held in memory they
+/// are sorted and spilled to a run file next to `spill_prefix`; runs
+/// are merged when finished.
+pub struct CellAccumulator {
+    cells: HashMap<u64, f64>,
+    merge: fn(f64, f64) -> f64,
+    max_cells: usize,
+    spill_prefix: PathBuf,
+    runs: Vec<PathBuf>,
+    /// Number of run files created, used to name the next one.
+    spilled: usize,
+}
+
+impl CellAccumulator {
+    pub fn new(spill_prefix: impl Into<PathBuf>) -> Self {
+        Self {
+            cells: HashMap::new(),
+            merge: |acc, val| acc + val,
+            max_cells: DEFAULT_MAX_CELLS,
+            spill_prefix: spill_prefix.into(),
+            runs: Vec::new(),
+            spilled: 0,
+        }
+    }
+
+    /// Sets the number of distinct cells held in memory before
+    /// spilling.
+    pub fn with_max_cells(mut self, max_cells: usize) -> Self {
+        self.max_cells = max_cells.max(1);
+        self
+    }
+
+    /// Sets `max_cells` so in-memory cells use about `bytes` of memory.
+    pub fn with_memory_limit(self, bytes: u64) -> Self {
+        let max_cells = usize::try_from(bytes / BYTES_PER_CELL).unwrap_or(usize::MAX);
+        self.with_max_cells(max_cells)
+    }
+
+    /// Sets how two values for the same cell are combined.
+    pub fn with_merge(mut self, merge: fn(f64, f64) -> f64) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    pub fn add(&mut self, h3_index: u64, val: f64) -> Result<(), GpwError> {
+        let merge = self.merge;
+        self.cells
+            .entry(h3_index)
+            .and_modify(|acc| *acc = merge(*acc, val))
+            .or_insert(val);
+        if self.cells.len() >= self.max_cells {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Merges the cells of `other` into this accumulator, taking over
+    /// any runs it spilled.
+    pub fn absorb(&mut self, mut other: CellAccumulator) -> Result<(), GpwError> {
+        if !other.runs.is_empty() && !other.cells.is_empty() {
+            other.spill()?;
+        }
+        self.runs.append(&mut other.runs);
+        // Sorted so spills, and therefore the output, don't depend on
+        // hash order.
+        for (h3_index, val) in other.take_sorted() {
+            self.add(h3_index, val)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the in-memory cells sorted by H3 index.
+    fn take_sorted(&mut self) -> Vec<(u64, f64)> {
+        let mut cells: Vec<(u64, f64)> = self.cells.drain().collect();
+        cells.sort_unstable_by_key(|(h3_index, _)| *h3_index);
+        cells
+    }
+
+    /// Creates the next run file, tracked so it's removed on drop.
+    fn create_run(&mut self) -> Result<BufWriter<File>, GpwError> {
+        let path = PathBuf::from(format!(
+            "{}.run{}",
+            self.spill_prefix.display(),
+            self.spilled
+        ));
+        self.spilled += 1;
+        let wtr = BufWriter::new(File::create(&path)?);
+        self.runs.push(path);
+        Ok(wtr)
+    }
+
+    fn spill(&mut self) -> Result<(), GpwError> {
+        let mut wtr = self.create_run()?;
+        for (h3_index, val) in self.take_sorted() {
+            write_run_record(&mut wtr, h3_index, val)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Calls `f` with every cell and its merged value in ascending H3
+    /// index order, removing any spilled runs.
+    pub fn finish<F>(mut self, mut f: F) -> Result<(), GpwError>
+    where
+        F: FnMut(u64, f64) -> Result<(), GpwError>,
+    {
+        if self.runs.is_empty() {
+            for (h3_index, val) in self.take_sorted() {
+                f(h3_index, val)?;
+            }
+            return Ok(());
+        }
+        if !self.cells.is_empty() {
+            self.spill()?;
+        }
+        // Merge the oldest runs into a new one until few enough are
+        // left to merge at once.
+        while self.runs.len() > MAX_MERGE_RUNS {
+            let mut wtr = self.create_run()?;
+            merge_runs(&self.runs[..MAX_MERGE_RUNS], self.merge, |h3_index, val| {
+                write_run_record(&mut wtr, h3_index, val)
+            })?;
+            wtr.flush()?;
+            for path in self.runs.drain(..MAX_MERGE_RUNS) {
+                fs::remove_file(path)?;
+            }
+        }
+        merge_runs(&self.runs, self.merge, f)
+    }
+}
+
+impl Drop for CellAccumulator {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Calls `f` with every cell in the sorted `runs`, in ascending H3
+/// index order, combining values for the same cell with `merge`.
+fn merge_runs<F>(runs: &[PathBuf], merge: fn(f64, f64) -> f64, mut f: F) -> Result<(), GpwError>
+where
+    F: FnMut(u64, f64) -> Result<(), GpwError>,
+{
+    let mut runs = runs
+        .iter()
+        .map(|path| Ok(BufReader::new(File::open(path)?)))
+        .collect::<Result<Vec<_>, GpwError>>()?;
+    let mut heads = BinaryHeap::new();
+    let mut vals = vec![0.0; runs.len()];
+    for (run_idx, run) in runs.iter_mut().enumerate() {
+        if let Some((h3_index, val)) = read_run_record(run)? {
+            vals[run_idx] = val;
+            heads.push(Reverse((h3_index, run_idx)));
+        }
+    }
+
+    let mut current: Option<(u64, f64)> = None;
+    while let Some(Reverse((h3_index, run_idx))) = heads.pop() {
+        let val = vals[run_idx];
+        current = match current {
+            Some((cur_index, cur_val)) if cur_index == h3_index => {
+                Some((cur_index, merge(cur_val, val)))
+            }
+            Some((cur_index, cur_val)) => {
+                f(cur_index, cur_val)?;
+                Some((h3_index, val))
+            }
+            None => Some((h3_index, val)),
+        };
+        if let Some((next_index, next_val)) = read_run_record(&mut runs[run_idx])? {
+            vals[run_idx] = next_val;
+            heads.push(Reverse((next_index, run_idx)));
+        }
+    }
+    if let Some((h3_index, val)) = current {
+        f(h3_index, val)?;
+    }
+    Ok(())
+}
+
+fn write_run_record<W: Write>(wtr: &mut W, h3_index: u64, val: f64) -> Result<(), GpwError> {
+    wtr.write_all(&h3_index.to_le_bytes())?;
+    wtr.write_all(&val.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_run_record<R: Read>(rdr: &mut R) -> Result<Option<(u64, f64)>, GpwError> {
+    let mut buf = [0; 16];
+    match rdr.read_exact(&mut buf) {
+        Ok(()) => {
+            let (h3_index, val) = buf.split_at(8);
+            Ok(Some((
+                u64::from_le_bytes(h3_index.try_into().expect("8 byte slice")),
+                f64::from_le_bytes(val.try_into().expect("8 byte slice")),
+            )))
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    fn accumulate(
+        records: &[(u64, f64)],
+        max_cells: usize,
+        merge: fn(f64, f64) -> f64,
+    ) -> Vec<(u64, f64)> {
+        let dir = TestDir::new(&format!("accumulate_{}", max_cells));
+        let prefix = dir.join("cells");
+        let mut cells = CellAccumulator::new(&prefix)
+            .with_max_cells(max_cells)
+            .with_merge(merge);
+        for (h3_index, val) in records {
+            cells.add(*h3_index, *val).unwrap();
+        }
+        let mut out = Vec::new();
+        cells
+            .finish(|h3_index, val| {
+                out.push((h3_index, val));
+                Ok(())
+            })
+            .unwrap();
+        let leftover = fs::read_dir(dir.join("")).unwrap().count();
+        assert_eq!(leftover, 0, "runs left behind");
+        out
+    }
+
+    #[test]
+    fn test_accumulate() {
+        let records = [(3, 1.0), (1, 2.0), (3, 0.5), (2, 1.0), (1, 1.0), (4, 2.0)];
+        let sum = |acc: f64, val: f64| acc + val;
+        let expected = vec![(1, 3.0), (2, 1.0), (3, 1.5), (4, 2.0)];
+        assert_eq!(accumulate(&records, DEFAULT_MAX_CELLS, sum), expected);
+        assert_eq!(accumulate(&records, 2, sum), expected);
+
+        let expected = vec![(1, 2.0), (2, 1.0), (3, 1.0), (4, 2.0)];
+        assert_eq!(accumulate(&records, DEFAULT_MAX_CELLS, f64::max), expected);
+        assert_eq!(accumulate(&records, 2, f64::max), expected);
+    }
+
+    #[test]
+    fn test_merge_in_passes() {
+        // Each record is spilled to its own run, many more than are
+        // merged at once.
+        let records: Vec<(u64, f64)> = (0..MAX_MERGE_RUNS as u64 * 4)
+            .map(|idx| (idx % 128, 1.0))
+            .collect();
+        let expected: Vec<(u64, f64)> = (0..128).map(|h3_index| (h3_index, 8.0)).collect();
+        assert_eq!(accumulate(&records, 1, |acc, val| acc + val), expected);
+    }
+
+    #[test]
+    fn test_absorb() {
+        let records = [(3, 1.0), (1, 2.0), (3, 0.5), (2, 1.0), (1, 1.0), (4, 2.0)];
+        for max_cells in [DEFAULT_MAX_CELLS, 1] {
+            let dir = TestDir::new(&format!("absorb_{}", max_cells));
+            let prefix = |name: &str| dir.join(name);
+            let mut cells = CellAccumulator::new(prefix("a")).with_max_cells(max_cells);
+            let mut other = CellAccumulator::new(prefix("b")).with_max_cells(max_cells);
+            for (h3_index, val) in &records[..3] {
+                cells.add(*h3_index, *val).unwrap();
+            }
+            for (h3_index, val) in &records[3..] {
+                other.add(*h3_index, *val).unwrap();
+            }
+            cells.absorb(other).unwrap();
+            let mut out = Vec::new();
+            cells
+                .finish(|h3_index, val| {
+                    out.push((h3_index, val));
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(out, vec![(1, 3.0), (2, 1.0), (3, 1.5), (4, 2.0)]);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{accumulate::CellAccumulator, error::GpwError, generate::Totals};
+use gpwformat::h3tess::{Aggregation, H3TessWriter};
+use hextree::{
+    compaction::Compactor,
+    h3ron::{FromH3Index, H3Cell, Index},
+};
+use std::io::{Seek, Write};
+
+/// Compacts seven sibling cells into their parent with `aggregation`,
+/// down to `resolution`.
+///
+/// Siblings are only compacted when all seven have a value, so a
+/// parent never stands in for cells that had no data.
+#[derive(Debug, Clone, Copy)]
+pub struct AggregateCompactor {
+    pub resolution: u8,
+    pub aggregation: Aggregation,
+}
+
+impl AggregateCompactor {
+    pub fn new(resolution: u8, aggregation: Aggregation) -> Self {
+        Self {
+            resolution,
+            aggregation,
+        }
+    }
+
+    /// Returns the value to insert for a record read from a source.
+    pub fn leaf_value(&self, val: f32) -> f32 {
+        match self.aggregation {
+            Aggregation::Count => 1.0,
+            _ => val,
+        }
+    }
+
+    /// Returns `true` if the total of all values is unchanged by
+    /// compaction, and can therefore be audited.
+    pub fn conserves_total(&self) -> bool {
+        conserves_total(self.aggregation)
+    }
+}
+
+fn conserves_total(aggregation: Aggregation) -> bool {
+    matches!(aggregation, Aggregation::Sum | Aggregation::Count)
+}
+
+impl Compactor<f32> for AggregateCompactor {
+    fn compact(&mut self, res: u8, children: [Option<&f32>; 7]) -> Option<f32> {
+        if res < self.resolution {
+            return None;
+        }
+        let mut vals = [0.0_f32; 7];
+        for (val, child) in vals.iter_mut().zip(children) {
+            *val = *child?;
+        }
+        let compacted = match self.aggregation {
+            Aggregation::Sum | Aggregation::Count => vals.iter().sum(),
+            // Siblings have (nearly) equal area, so their plain mean is
+            // the area-weighted mean.
+            Aggregation::Mean => vals.iter().sum::<f32>() / 7.0,
+            Aggregation::Min => vals.iter().copied().fold(f32::INFINITY, f32::min),
+            Aggregation::Max => vals.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        };
+        Some(compacted)
+    }
+}
+
+/// Rolls every record up to its parent at exactly `resolution`.
+///
+/// Unlike [`AggregateCompactor`], parents are produced even when some
+/// of their children have no value; missing children count as zero
+/// in sums and means.
+pub struct Rollup {
+    resolution: u8,
+    aggregation: Aggregation,
+    cells: CellAccumulator,
+    totals: Totals,
+}
+
+impl Rollup {
+    pub fn new(resolution: u8, aggregation: Aggregation, cells: CellAccumulator) -> Self {
+        let merge: fn(f64, f64) -> f64 = match aggregation {
+            Aggregation::Min => f64::min,
+            Aggregation::Max => f64::max,
+            Aggregation::Sum | Aggregation::Mean | Aggregation::Count => |acc, val| acc + val,
+        };
+        Self {
+            resolution,
+            aggregation,
+            cells: cells.with_merge(merge),
+            totals: Totals::default(),
+        }
+    }
+
+    pub fn add(&mut self, h3_index: u64, val: f32) -> Result<(), GpwError> {
+        let cell = H3Cell::from_h3index(h3_index);
+        let resolution = cell.resolution();
+        if resolution < self.resolution {
+            return Err(GpwError::CoarseCell {
+                h3_index,
+                resolution,
+                target: self.resolution,
+            });
+        }
+        let parent = cell
+            .get_parent(self.resolution)
+            .map_err(|e| ("H3 cell", e))?;
+        let val = match self.aggregation {
+            Aggregation::Count => 1.0,
+            // A cell n resolutions finer covers 1/7^n of its parent.
+            Aggregation::Mean => {
+                f64::from(val) / 7_f64.powi(i32::from(resolution - self.resolution))
+            }
+            Aggregation::Sum | Aggregation::Min | Aggregation::Max => f64::from(val),
+        };
+        self.totals.source += val;
+        self.cells.add(*parent, val)
+    }
+
+    /// Merges a rollup of other records to the same resolution into
+    /// this one.
+    pub fn absorb(&mut self, other: Rollup) -> Result<(), GpwError> {
+        debug_assert_eq!(self.resolution, other.resolution);
+        self.totals.source += other.totals.source;
+        self.cells.absorb(other.cells)
+    }
+
+    /// Returns `true` if the total of all values is unchanged by the
+    /// rollup, and can therefore be audited.
+    pub fn conserves_total(&self) -> bool {
+        conserves_total(self.aggregation)
+    }
+
+    /// Writes every parent cell to `dst`, sorted by H3 index.
+    pub fn finish<W: Write + Seek>(self, dst: &mut H3TessWriter<W>) -> Result<Totals, GpwError> {
+        let mut totals = self.totals;
+        self.cells.finish(|h3_index, val| {
+            let val = val as f32;
+            totals.written += f64::from(val);
+            Ok(dst.write(h3_index, val)?)
+        })?;
+        Ok(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accumulate::DEFAULT_MAX_CELLS, test_util::H3TessFixture};
+
+    #[test]
+    fn test_compact() {
+        let vals = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        let children = vals.each_ref().map(Some);
+        let compact = |aggregation| AggregateCompactor::new(8, aggregation).compact(8, children);
+        assert_eq!(compact(Aggregation::Sum), Some(28.0));
+        assert_eq!(compact(Aggregation::Count), Some(28.0));
+        assert_eq!(compact(Aggregation::Mean), Some(4.0));
+        assert_eq!(compact(Aggregation::Min), Some(1.0));
+        assert_eq!(compact(Aggregation::Max), Some(7.0));
+
+        let mut compactor = AggregateCompactor::new(8, Aggregation::Sum);
+        assert_eq!(compactor.compact(7, children), None);
+        let mut missing = children;
+        missing[3] = None;
+        assert_eq!(compactor.compact(8, missing), None);
+    }
+
+    #[test]
+    fn test_rollup() {
+        let cell = H3Cell::from_coordinate(geo::coord! {x: 10.0, y: 45.0}, 10).unwrap();
+        let parent = cell.get_parent(9).unwrap();
+        let elsewhere = H3Cell::from_coordinate(geo::coord! {x: -70.0, y: -30.0}, 8).unwrap();
+        let records = [(*cell, 2.0), (*parent, 3.0), (*elsewhere, 1.0)];
+        let target = *cell.get_parent(8).unwrap();
+
+        let rollup = |aggregation, max_cells| {
+            let mut out = H3TessFixture::new("rollup", 8);
+            let mut rollup = Rollup::new(8, aggregation, out.cells(max_cells));
+            for (h3_index, val) in records {
+                rollup.add(h3_index, val).unwrap();
+            }
+            rollup.finish(&mut out.dst).unwrap();
+            let mut records = out.records();
+            records.retain(|(h3_index, _)| *h3_index == target);
+            records
+        };
+        // Spilling every record to its own run gives the same result.
+        for max_cells in [DEFAULT_MAX_CELLS, 1] {
+            assert_eq!(rollup(Aggregation::Sum, max_cells), vec![(target, 5.0)]);
+            assert_eq!(rollup(Aggregation::Count, max_cells), vec![(target, 2.0)]);
+            assert_eq!(rollup(Aggregation::Max, max_cells), vec![(target, 3.0)]);
+            assert_eq!(rollup(Aggregation::Min, max_cells), vec![(target, 2.0)]);
+            assert_eq!(
+                rollup(Aggregation::Mean, max_cells),
+                vec![(target, (2.0 / 49.0 + 3.0 / 7.0) as f32)]
+            );
+        }
+
+        let mut rollup = Rollup::new(9, Aggregation::Sum, CellAccumulator::new("unused"));
+        assert!(rollup.add(*elsewhere, 1.0).is_err());
+    }
+}
@@ -1,9 +1,13 @@
+use crate::{clip::BoundingBox, generate::Weighting, raster::NegativePolicy};
 use clap::Parser;
+use gpwformat::h3tess::Aggregation;
+use std::{ops::RangeInclusive, path::Path, str::FromStr};
 
 #[derive(Parser, Debug)]
 pub enum Args {
     Tessellate(Tessellate),
     Combine(Combine),
+    Rasterize(Rasterize),
 }
 
 /// Tessellate global world population (GPW) asc file grids into H3
@@ -11,25 +15,238 @@ pub enum Args {
 #[derive(Parser, Debug)]
 pub struct Tessellate {
     /// Intermediate H3 resolution.
-    #[arg(short, long, default_value_t = 10)]
+    #[arg(short, long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=15))]
     pub resolution: u8,
-    /// Input GPW ASCII file.
+    /// How each pixel's population is distributed over H3 cells.
+    #[arg(short, long, value_enum, default_value_t = Weighting::Even)]
+    pub weighting: Weighting,
+    /// How negative samples other than NODATA are handled.
+    #[arg(long, value_enum, default_value_t = NegativePolicy::Error)]
+    pub negative: NegativePolicy,
+    /// Input GPW ASCII files, optionally gzip-compressed (`.asc.gz`)
+    /// or zip archives of `.asc` files, or GeoTIFF (`.tif`) files.
     pub sources: Vec<std::path::PathBuf>,
     /// Output directory.
     #[arg(short, long)]
     pub outdir: std::path::PathBuf,
+    /// Treat all sources as tiles of a single raster, written to
+    /// `<outdir>/<MOSAIC>.res{N}.h3tess`, so cells on the seams between
+    /// tiles are split consistently.
+    #[arg(long)]
+    pub mosaic: Option<String>,
+    /// Only read pixels overlapping `minlon,minlat,maxlon,maxlat`.
+    #[arg(long, allow_hyphen_values = true)]
+    pub bbox: Option<BoundingBox>,
+    /// GeoJSON file of polygons. Only pixels overlapping their
+    /// bounding box are read, and cells whose centroid lies outside
+    /// them are dropped.
+    #[arg(long)]
+    pub clip: Option<std::path::PathBuf>,
+    /// Maximum relative difference between raster and written
+    /// population before failing.
+    #[arg(long, default_value_t = 1e-5)]
+    pub tolerance: f64,
+    /// Continue with the remaining sources when one fails.
+    #[arg(short, long)]
+    pub keep_going: bool,
+    /// Number of worker threads, defaults to the number of CPUs.
+    /// Output does not depend on this.
+    #[arg(short, long)]
+    pub threads: Option<usize>,
+    /// Maximum number of sources being parsed and tessellated at once.
+    #[arg(short, long, default_value_t = 2)]
+    pub jobs: usize,
 }
 
 /// Combine multiple h3tess files into a single serialized H3 map at
 /// the specified resolution.
 #[derive(Parser, Debug)]
 pub struct Combine {
-    /// H3 resolution.
-    #[arg(short, long, default_value_t = 8)]
-    pub resolution: u8,
+    /// H3 resolution, or an inclusive range such as `4-10` to write
+    /// one file per resolution.
+    #[arg(short, long, default_value = "8")]
+    pub resolution: ResolutionRange,
+    /// How values of child cells are combined into their parent.
+    #[arg(short, long, value_enum, default_value_t = Aggregation::Sum)]
+    pub aggregate: Aggregation,
     /// h3tess source files.
     pub sources: Vec<std::path::PathBuf>,
-    /// Output file.
+    /// Output file. With a range of resolutions, `res{N}` is inserted
+    /// before the extension of each file.
     #[arg(short, long)]
     pub output: std::path::PathBuf,
+    /// Maximum relative difference between source and combined
+    /// population before failing.
+    #[arg(long, default_value_t = 1e-5)]
+    pub tolerance: f64,
+    /// Read sources written before h3tess files had a header.
+    #[arg(long)]
+    pub legacy: bool,
+    /// Only merge cells whose seven children all have a value, leaving
+    /// partially covered areas at their original resolution instead of
+    /// rolling every cell up to `resolution`.
+    #[arg(long)]
+    pub lossless: bool,
+    /// Approximate memory to use for cells, such as `512M` or `8G`.
+    /// Beyond this, sorted runs are spilled next to the output and
+    /// merged at the end. Defaults to 4G. Not supported with
+    /// `--lossless`.
+    #[arg(long, value_parser = parse_bytes)]
+    pub memory_limit: Option<u64>,
+    /// Number of sources read in parallel, defaults to the number of
+    /// CPUs. Output does not depend on this.
+    #[arg(short, long)]
+    pub threads: Option<usize>,
+}
+
+/// Distribute the values of an h3tess file over the pixels of a grid
+/// and write it as an ESRI ASCII file.
+#[derive(Parser, Debug)]
+pub struct Rasterize {
+    /// h3tess source file.
+    pub source: std::path::PathBuf,
+    /// Output ESRI ASCII file.
+    #[arg(short, long)]
+    pub output: std::path::PathBuf,
+    /// Raster whose grid is written, such as the GPW file an h3tess
+    /// file was tessellated from. Otherwise the grid is given by
+    /// `--ncols`, `--nrows`, `--xllcorner`, `--yllcorner` and
+    /// `--cellsize`. The grid is held in memory at four bytes per
+    /// pixel, about 3.7 GB for a global 30 arc-second grid.
+    #[arg(long, conflicts_with_all = ["ncols", "nrows", "xllcorner", "yllcorner", "cellsize"])]
+    pub like: Option<std::path::PathBuf>,
+    #[arg(long, required_unless_present = "like")]
+    pub ncols: Option<usize>,
+    #[arg(long, required_unless_present = "like")]
+    pub nrows: Option<usize>,
+    /// Longitude of the lower-left corner of the grid.
+    #[arg(long, required_unless_present = "like", allow_hyphen_values = true)]
+    pub xllcorner: Option<f64>,
+    /// Latitude of the lower-left corner of the grid.
+    #[arg(long, required_unless_present = "like", allow_hyphen_values = true)]
+    pub yllcorner: Option<f64>,
+    /// Pixel size in degrees.
+    #[arg(long, required_unless_present = "like")]
+    pub cellsize: Option<f64>,
+    /// Value written for pixels no cell overlaps.
+    #[arg(long, default_value_t = -9999.0, allow_hyphen_values = true)]
+    pub nodata_value: f64,
+    /// Maximum relative difference between source and rasterized
+    /// population, less any outside the grid, before failing.
+    #[arg(long, default_value_t = 1e-5)]
+    pub tolerance: f64,
+    /// Read a source written before h3tess files had a header.
+    #[arg(long)]
+    pub legacy: bool,
+    /// Number of worker threads, defaults to the number of CPUs.
+    /// Output does not depend on this.
+    #[arg(short, long)]
+    pub threads: Option<usize>,
+}
+
+/// An inclusive range of H3 resolutions, parsed from `8` or `4-10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolutionRange {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl ResolutionRange {
+    pub fn iter(&self) -> RangeInclusive<u8> {
+        self.min..=self.max
+    }
+
+    pub fn is_single(&self) -> bool {
+        self.min == self.max
+    }
+
+    /// Returns the path of the file for `resolution`, which is `output`
+    /// itself unless this is a range.
+    pub fn output_path(&self, output: &Path, resolution: u8) -> std::path::PathBuf {
+        if self.is_single() {
+            return output.to_path_buf();
+        }
+        let mut path = output.to_path_buf();
+        let extension = output
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "h3tess".to_string());
+        path.set_extension(format!("res{}.{}", resolution, extension));
+        path
+    }
+}
+
+impl FromStr for ResolutionRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| match s.trim().parse::<u8>() {
+            Ok(res) if res <= 15 => Ok(res),
+            _ => Err(format!("{:?} is not an H3 resolution (0-15)", s)),
+        };
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if min > max {
+            return Err(format!("empty resolution range {:?}", s));
+        }
+        Ok(Self { min, max })
+    }
+}
+
+/// Parses a byte count with an optional `K`, `M`, `G` or `T` binary
+/// suffix.
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((idx, suffix)) if suffix.is_ascii_alphabetic() => {
+            let shift = match suffix.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix in {:?}", s)),
+            };
+            (&s[..idx], shift)
+        }
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("{:?} is not a size", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolution_range() {
+        let range = |s: &str| s.parse::<ResolutionRange>();
+        assert_eq!(range("8"), Ok(ResolutionRange { min: 8, max: 8 }));
+        assert_eq!(range("4-10"), Ok(ResolutionRange { min: 4, max: 10 }));
+        assert!(range("10-4").is_err());
+        assert!(range("16").is_err());
+        assert!(range("4-").is_err());
+
+        let output = Path::new("out/world.h3tess");
+        assert_eq!(range("8").unwrap().output_path(output, 8), output);
+        assert_eq!(
+            range("4-10").unwrap().output_path(output, 6),
+            Path::new("out/world.res6.h3tess")
+        );
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1024"), Ok(1024));
+        assert_eq!(parse_bytes("512M"), Ok(512 << 20));
+        assert_eq!(parse_bytes("8g"), Ok(8 << 30));
+        assert!(parse_bytes("8X").is_err());
+        assert!(parse_bytes("G").is_err());
+        assert!(parse_bytes("99999999T").is_err());
+    }
 }
@@ -0,0 +1,334 @@
+use crate::{
+    error::GpwError,
+    gpwascii::GpwAsciiHeader,
+    raster::{Raster, Row},
+};
+use geo::{BoundingRect, Contains, Geometry, GeometryCollection, MultiPolygon, Point};
+use hextree::h3ron::{FromH3Index, H3Cell, ToCoordinate};
+use std::{ops::Range, path::Path, str::FromStr};
+
+/// A longitude/latitude rectangle, parsed from
+/// `minlon,minlat,maxlon,maxlat`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl BoundingBox {
+    fn intersection(&self, other: &BoundingBox) -> Option<BoundingBox> {
+        let bbox = BoundingBox {
+            min_lon: self.min_lon.max(other.min_lon),
+            min_lat: self.min_lat.max(other.min_lat),
+            max_lon: self.max_lon.min(other.max_lon),
+            max_lat: self.max_lat.min(other.max_lat),
+        };
+        (bbox.min_lon < bbox.max_lon && bbox.min_lat < bbox.max_lat).then_some(bbox)
+    }
+
+    /// Returns the rows and columns of `header`'s grid overlapping this
+    /// box, which may be empty.
+    pub fn window(&self, header: &GpwAsciiHeader) -> Window {
+        let top = header.yllcorner + header.nrows as f64 * header.dy;
+        let col = |lon: f64| ((lon - header.xllcorner) / header.dx).clamp(0.0, header.ncols as f64);
+        let row = |lat: f64| ((top - lat) / header.dy).clamp(0.0, header.nrows as f64);
+        Window {
+            rows: row(self.max_lat).floor() as usize..row(self.min_lat).ceil() as usize,
+            cols: col(self.min_lon).floor() as usize..col(self.max_lon).ceil() as usize,
+        }
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|coord| coord.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("{:?} is not minlon,minlat,maxlon,maxlat", s))?;
+        let [min_lon, min_lat, max_lon, max_lat] = coords[..] else {
+            return Err(format!("{:?} is not minlon,minlat,maxlon,maxlat", s));
+        };
+        if min_lon >= max_lon || min_lat >= max_lat {
+            return Err(format!("empty bounding box {:?}", s));
+        }
+        Ok(Self {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
+    }
+}
+
+/// The region of a raster to tessellate.
+///
+/// Only pixels overlapping `bbox` are read, and are tessellated in
+/// full. With a polygon, cells whose centroid lies outside it are
+/// dropped as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clip {
+    pub bbox: BoundingBox,
+    pub polygon: Option<MultiPolygon>,
+}
+
+impl Clip {
+    pub fn from_bbox(bbox: BoundingBox) -> Self {
+        Self {
+            bbox,
+            polygon: None,
+        }
+    }
+
+    /// Clips to `polygon`, reading only the pixels overlapping its
+    /// bounding box.
+    pub fn from_polygon(polygon: MultiPolygon) -> Result<Self, GpwError> {
+        let rect = polygon
+            .bounding_rect()
+            .ok_or_else(|| GpwError::InvalidClip("no polygons".to_string()))?;
+        Ok(Self {
+            bbox: BoundingBox {
+                min_lon: rect.min().x,
+                min_lat: rect.min().y,
+                max_lon: rect.max().x,
+                max_lat: rect.max().y,
+            },
+            polygon: Some(polygon),
+        })
+    }
+
+    /// Clips to the polygons and multipolygons in a GeoJSON file. Other
+    /// geometries are ignored.
+    pub fn from_geojson(path: &Path) -> Result<Self, GpwError> {
+        let geojson = std::fs::read_to_string(path)?
+            .parse::<geojson::GeoJson>()
+            .map_err(|e| ("GeoJSON", e))?;
+        let collection: GeometryCollection =
+            geojson::quick_collection(&geojson).map_err(|e| ("GeoJSON", e))?;
+        let mut polygons = Vec::new();
+        for geometry in collection.0 {
+            match geometry {
+                Geometry::Polygon(polygon) => polygons.push(polygon),
+                Geometry::MultiPolygon(multi) => polygons.extend(multi.0),
+                _ => (),
+            }
+        }
+        Self::from_polygon(MultiPolygon::new(polygons))
+    }
+
+    /// Further restricts the pixels read to `bbox`.
+    pub fn with_bbox(mut self, bbox: BoundingBox) -> Result<Self, GpwError> {
+        self.bbox = self.bbox.intersection(&bbox).ok_or_else(|| {
+            GpwError::InvalidClip("bounding box doesn't overlap the clip polygon".to_string())
+        })?;
+        Ok(self)
+    }
+
+    /// Returns the rows and columns of `header`'s grid overlapping the
+    /// bounding box, which may be empty.
+    pub fn window(&self, header: &GpwAsciiHeader) -> Window {
+        self.bbox.window(header)
+    }
+
+    /// Returns `false` if the centroid of `h3_index` lies outside the
+    /// clip polygon.
+    pub fn contains_cell(&self, h3_index: u64) -> bool {
+        let Some(polygon) = &self.polygon else {
+            return true;
+        };
+        match H3Cell::from_h3index(h3_index).to_coordinate() {
+            Ok(center) => polygon.contains(&Point::from(center)),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Rows and columns of a raster grid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    pub rows: Range<usize>,
+    pub cols: Range<usize>,
+}
+
+impl Window {
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty() || self.cols.is_empty()
+    }
+
+    /// Returns the geometry of this window of `header`'s grid.
+    pub fn header(&self, header: &GpwAsciiHeader) -> GpwAsciiHeader {
+        GpwAsciiHeader {
+            ncols: self.cols.len(),
+            nrows: self.rows.len(),
+            xllcorner: header.xllcorner + self.cols.start as f64 * header.dx,
+            yllcorner: header.yllcorner + (header.nrows - self.rows.end) as f64 * header.dy,
+            ..header.clone()
+        }
+    }
+}
+
+/// A window of another raster, read as a raster of its own.
+///
+/// Rows above the window are skipped and columns outside it are not
+/// parsed, as far as the underlying format allows, and nothing below
+/// the window is read.
+pub struct Clipped {
+    raster: Box<dyn Raster + Send>,
+    header: GpwAsciiHeader,
+    window: Window,
+    /// Index of the next row of the underlying raster.
+    next_row: usize,
+    done: bool,
+}
+
+impl Clipped {
+    pub fn new(mut raster: Box<dyn Raster + Send>, window: Window) -> Self {
+        let header = window.header(raster.header());
+        raster.restrict_columns(window.cols.clone());
+        Self {
+            raster,
+            header,
+            window,
+            next_row: 0,
+            done: false,
+        }
+    }
+
+    fn parse_row(&mut self) -> Result<Option<Row>, GpwError> {
+        if self.window.is_empty() || self.next_row >= self.window.rows.end {
+            return Ok(None);
+        }
+        if self.next_row < self.window.rows.start {
+            self.raster
+                .skip_rows(self.window.rows.start - self.next_row)?;
+            self.next_row = self.window.rows.start;
+        }
+        let (row_idx, mut row) = self.raster.next().ok_or_else(|| GpwError::MissingRows {
+            file: None,
+            expected: self.raster.header().nrows,
+            found: self.next_row,
+        })??;
+        self.next_row += 1;
+        row.truncate(self.window.cols.end);
+        row.drain(..self.window.cols.start);
+        Ok(Some((row_idx - self.window.rows.start, row)))
+    }
+}
+
+impl Iterator for Clipped {
+    type Item = Result<Row, GpwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let row = self.parse_row();
+        self.done = !matches!(row, Ok(Some(_)));
+        row.transpose()
+    }
+}
+
+impl Raster for Clipped {
+    fn header(&self) -> &GpwAsciiHeader {
+        &self.header
+    }
+
+    fn suspicious(&self) -> usize {
+        self.raster.suspicious()
+    }
+
+    fn read_remainder(&mut self) -> Result<(), GpwError> {
+        self.raster.read_remainder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpwascii::GpwAsciiRows;
+    use geo::polygon;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_bounding_box() {
+        assert_eq!(
+            "-10,20.5, 30,40".parse::<BoundingBox>(),
+            Ok(BoundingBox {
+                min_lon: -10.0,
+                min_lat: 20.5,
+                max_lon: 30.0,
+                max_lat: 40.0,
+            })
+        );
+        assert!("-10,20,30".parse::<BoundingBox>().is_err());
+        assert!("10,20,-30,40".parse::<BoundingBox>().is_err());
+        assert!("a,b,c,d".parse::<BoundingBox>().is_err());
+    }
+
+    #[test]
+    fn test_clipped() {
+        let file = "ncols 4\nnrows 3\nxllcorner 0\nyllcorner 0\ncellsize 1\nNODATA_value -9999\n\
+                    1 2 3 4\n5 6 x 8\n9 10 11 12\n";
+        let raster = GpwAsciiRows::new(Cursor::new(file.as_bytes().to_vec())).unwrap();
+        let header = raster.header.clone();
+        let clip = Clip::from_bbox("0.5,0.5,1.5,1.5".parse().unwrap());
+        let window = clip.window(&header);
+        assert_eq!(
+            window,
+            Window {
+                rows: 1..3,
+                cols: 0..2
+            }
+        );
+        let clipped = Clipped::new(Box::new(raster), window);
+        assert_eq!(
+            clipped.header(),
+            &GpwAsciiHeader {
+                ncols: 2,
+                nrows: 2,
+                xllcorner: 0.0,
+                yllcorner: 0.0,
+                dx: 1.0,
+                dy: 1.0,
+                nodata_value: Some(-9999.0),
+            }
+        );
+        // The invalid sample outside the window is never parsed.
+        let rows = clipped.collect::<Result<Vec<Row>, _>>().unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (0, vec![Some(5.0), Some(6.0)]),
+                (1, vec![Some(9.0), Some(10.0)]),
+            ]
+        );
+
+        let elsewhere = Clip::from_bbox("10,10,11,11".parse().unwrap());
+        assert!(elsewhere.window(&header).is_empty());
+    }
+
+    #[test]
+    fn test_contains_cell() {
+        let polygon = polygon![
+            (x: 0.0, y: 0.0),
+            (x: 1.0, y: 0.0),
+            (x: 1.0, y: 1.0),
+            (x: 0.0, y: 1.0),
+        ];
+        let clip = Clip::from_polygon(MultiPolygon::new(vec![polygon])).unwrap();
+        assert_eq!(clip.bbox, "0,0,1,1".parse().unwrap());
+        let inside = H3Cell::from_coordinate(geo::coord! {x: 0.5, y: 0.5}, 8).unwrap();
+        let outside = H3Cell::from_coordinate(geo::coord! {x: 1.5, y: 0.5}, 8).unwrap();
+        assert!(clip.contains_cell(*inside));
+        assert!(!clip.contains_cell(*outside));
+
+        let clip = clip.with_bbox("0.5,0.5,2,2".parse().unwrap()).unwrap();
+        assert_eq!(clip.bbox, "0.5,0.5,1,1".parse().unwrap());
+        assert!(clip.with_bbox("2,2,3,3".parse().unwrap()).is_err());
+        assert!(Clip::from_polygon(MultiPolygon::new(Vec::new())).is_err());
+    }
+}
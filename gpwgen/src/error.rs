@@ -1,10 +1,161 @@
-use std::io;
+use gpwformat::FormatError;
+use std::{fmt, io};
+use tiff::TiffError;
+use zip::result::ZipError;
 
 #[derive(Debug)]
 pub enum GpwError {
     Io(io::Error),
+    Zip(ZipError),
+    Tiff(TiffError),
+    Format(FormatError),
     /// Generic parsing error
-    Parse(&'static str, Option<Box<dyn std::fmt::Debug>>),
+    Parse(&'static str, Option<Box<dyn fmt::Debug + Send + Sync>>),
+    /// A data row has fewer cells than the header's `ncols`.
+    ShortRow {
+        location: Location,
+        expected: usize,
+        found: usize,
+    },
+    /// A data row has more cells than the header's `ncols`.
+    LongRow {
+        location: Location,
+        expected: usize,
+        found: usize,
+    },
+    /// The file ended before the header's `nrows` rows were read.
+    MissingRows {
+        file: Option<String>,
+        expected: usize,
+        found: usize,
+    },
+    /// Non-whitespace content follows the last data row.
+    TrailingData {
+        location: Location,
+    },
+    /// A cell is neither NODATA nor a number.
+    InvalidCell {
+        location: Location,
+        value: String,
+    },
+    /// A cell holds a negative value other than NODATA.
+    NegativeCell {
+        location: Location,
+        value: f32,
+    },
+    /// A source couldn't be opened or its header is invalid.
+    Open {
+        file: String,
+        error: Box<GpwError>,
+    },
+    /// Member rasters can't be combined into a mosaic.
+    InvalidMosaic(String),
+    /// A clip region is empty.
+    InvalidClip(String),
+    /// An H3 cell is coarser than the resolution it is rolled up to.
+    CoarseCell {
+        h3_index: u64,
+        resolution: u8,
+        target: u8,
+    },
+}
+
+/// Position of a cell within a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Option<String>,
+    /// One-based line number.
+    pub line: usize,
+    /// One-based cell number within the line.
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.file.as_deref().unwrap_or("<input>"),
+            self.line,
+            self.column
+        )
+    }
+}
+
+impl fmt::Display for GpwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpwError::Io(e) => write!(f, "{}", e),
+            GpwError::Zip(e) => write!(f, "{}", e),
+            GpwError::Tiff(e) => write!(f, "{}", e),
+            GpwError::Format(e) => write!(f, "{}", e),
+            GpwError::Parse(field, Some(e)) => write!(f, "failed to parse {}: {:?}", field, e),
+            GpwError::Parse(field, None) => write!(f, "failed to parse {}", field),
+            GpwError::ShortRow {
+                location,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: short row, expected {} cells, found {}",
+                location, expected, found
+            ),
+            GpwError::LongRow {
+                location,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: long row, expected {} cells, found {}",
+                location, expected, found
+            ),
+            GpwError::MissingRows {
+                file,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: expected {} rows, found {}",
+                file.as_deref().unwrap_or("<input>"),
+                expected,
+                found
+            ),
+            GpwError::TrailingData { location } => {
+                write!(f, "{}: unexpected data after last row", location)
+            }
+            GpwError::InvalidCell { location, value } => {
+                write!(f, "{}: invalid cell value {:?}", location, value)
+            }
+            GpwError::NegativeCell { location, value } => {
+                write!(f, "{}: negative cell value {}", location, value)
+            }
+            GpwError::Open { file, error } => write!(f, "{}: {}", file, error),
+            GpwError::InvalidMosaic(msg) => write!(f, "invalid mosaic: {}", msg),
+            GpwError::InvalidClip(msg) => write!(f, "invalid clip region: {}", msg),
+            GpwError::CoarseCell {
+                h3_index,
+                resolution,
+                target,
+            } => write!(
+                f,
+                "cell {:x} at resolution {} is coarser than resolution {}",
+                h3_index, resolution, target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GpwError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GpwError::Io(e) => Some(e),
+            GpwError::Zip(e) => Some(e),
+            GpwError::Tiff(e) => Some(e),
+            GpwError::Format(e) => Some(e),
+            GpwError::Open { error, .. } => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for GpwError {
@@ -13,7 +164,25 @@ impl From<io::Error> for GpwError {
     }
 }
 
-impl<E: std::fmt::Debug + 'static> From<(&'static str, E)> for GpwError {
+impl From<ZipError> for GpwError {
+    fn from(e: ZipError) -> Self {
+        GpwError::Zip(e)
+    }
+}
+
+impl From<TiffError> for GpwError {
+    fn from(e: TiffError) -> Self {
+        GpwError::Tiff(e)
+    }
+}
+
+impl From<FormatError> for GpwError {
+    fn from(e: FormatError) -> Self {
+        GpwError::Format(e)
+    }
+}
+
+impl<E: fmt::Debug + Send + Sync + 'static> From<(&'static str, E)> for GpwError {
     fn from((field, e): (&'static str, E)) -> Self {
         GpwError::Parse(field, Some(Box::new(e)))
     }
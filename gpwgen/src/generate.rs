@@ -1,16 +1,106 @@
-use crate::gpwascii::{GpwAscii, GpwAsciiHeader};
-use geo::{coord, line_string, Polygon};
-use hextree::h3ron;
+use crate::{
+    accumulate::CellAccumulator,
+    clip::Clip,
+    error::GpwError,
+    gpwascii::GpwAsciiHeader,
+    raster::{Raster, Row},
+};
+use geo::{coord, line_string, Area, BooleanOps, Centroid, LineString, Point, Polygon};
+use gpwformat::h3tess::H3TessWriter;
+use hextree::h3ron::{self, FromH3Index, H3Cell, ToPolygon};
 use rayon::prelude::*;
-use std::io::Write;
+use std::io::{Seek, Write};
 
-pub fn tessalate_grid(header: &GpwAsciiHeader, row: usize, col: usize) -> Vec<u64> {
-    let grid_bottom_degs = header.yllcorner + header.cellsize * (header.nrows - row - 1) as f64;
-    let grid_top_degs = grid_bottom_degs + header.cellsize;
-    let grid_left_degs = header.xllcorner + header.cellsize * col as f64;
-    let grid_right_degs = grid_left_degs + header.cellsize;
+/// Average H3 hexagon edge length in kilometers, indexed by resolution.
+const H3_AVG_EDGE_LEN_KM: [f64; 16] = [
+    1281.256011,
+    483.0568391,
+    182.5129565,
+    68.97922179,
+    26.07175968,
+    9.854090990,
+    3.724532667,
+    1.406475763,
+    0.531414010,
+    0.200786148,
+    0.075863783,
+    0.028663897,
+    0.010830188,
+    0.004092010,
+    0.001546100,
+    0.000584169,
+];
 
-    let grid_cell_poly = Polygon::new(
+/// Kilometers per degree at the equator.
+const KM_PER_DEG: f64 = 111.32;
+
+/// How a pixel's value is distributed over the H3 cells it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Weighting {
+    /// Split evenly over every cell whose centroid lies in the pixel.
+    Even,
+    /// Split proportionally to the area of each cell overlapping the
+    /// pixel.
+    Area,
+}
+
+/// Returns `true` if an average H3 cell at `resolution` covers more
+/// area than a single raster pixel at the equator.
+///
+/// Even weighting only assigns a pixel to cells whose centroid it
+/// contains, so when cells are larger than pixels many pixels fall
+/// back to the single cell containing their center.
+pub fn cells_coarser_than_pixels(header: &GpwAsciiHeader, resolution: u8) -> bool {
+    let edge_km = H3_AVG_EDGE_LEN_KM[resolution as usize];
+    let cell_area_km2 = 3.0 * 3.0_f64.sqrt() / 2.0 * edge_km * edge_km;
+    let pixel_area_km2 = header.dx * KM_PER_DEG * header.dy * KM_PER_DEG;
+    cell_area_km2 > pixel_area_km2
+}
+
+/// Returns the H3 cells covering the pixel at (`row`, `col`) paired
+/// with the fraction of the pixel's value each cell receives.
+///
+/// The returned weights always sum to one. Pixels which no cell
+/// could be assigned to are given entirely to the cell containing
+/// the pixel's center.
+pub fn tessalate_grid(
+    header: &GpwAsciiHeader,
+    resolution: u8,
+    weighting: Weighting,
+    row: usize,
+    col: usize,
+) -> Vec<(u64, f64)> {
+    let grid_cell_poly = pixel_polygon(header, row, col);
+    let weighted = match weighting {
+        Weighting::Even => {
+            let hexes = h3ron::polygon_to_cells(&grid_cell_poly, resolution).unwrap();
+            let weight = 1.0 / hexes.count() as f64;
+            hexes.iter().map(|hex| (*hex, weight)).collect()
+        }
+        Weighting::Area => area_weighted_cells(&grid_cell_poly, resolution),
+    };
+    if weighted.is_empty() {
+        vec![(centroid_cell(&grid_cell_poly, resolution), 1.0)]
+    } else {
+        weighted
+    }
+}
+
+/// Returns the cell containing the center of `pixel`.
+fn centroid_cell(pixel: &Polygon, resolution: u8) -> u64 {
+    let center = pixel.centroid().unwrap();
+    *H3Cell::from_coordinate(center.0, resolution).unwrap()
+}
+
+pub(crate) fn pixel_polygon(header: &GpwAsciiHeader, row: usize, col: usize) -> Polygon {
+    let grid_bottom_degs = header.yllcorner + header.dy * (header.nrows - row - 1) as f64;
+    // Rounding in the header must not push polar rows past the pole.
+    let grid_top_degs = (grid_bottom_degs + header.dy).clamp(-90.0, 90.0);
+    let grid_bottom_degs = grid_bottom_degs.clamp(-90.0, 90.0);
+    let grid_left_degs = header.xllcorner + header.dx * col as f64;
+    let grid_right_degs = grid_left_degs + header.dx;
+
+    Polygon::new(
         line_string![
             // lower-left
             coord! {x: grid_left_degs, y: grid_bottom_degs},
@@ -24,51 +114,246 @@ pub fn tessalate_grid(header: &GpwAsciiHeader, row: usize, col: usize) -> Vec<u6
             coord! {x: grid_left_degs, y: grid_bottom_degs}
         ],
         vec![],
-    );
-    // Tesselate at res 10 so we can handle the two coordinate systems
-    // drifting.
-    let hexes = h3ron::polygon_to_cells(&grid_cell_poly, 10).unwrap();
-    hexes.iter().map(|hex| *hex).collect()
+    )
+}
+
+/// Intersects `pixel` with every H3 cell that may overlap it and
+/// weights each cell by its share of the overlapping area.
+fn area_weighted_cells(pixel: &Polygon, resolution: u8) -> Vec<(u64, f64)> {
+    let overlaps = pixel_overlaps(pixel, resolution);
+    let total: f64 = overlaps.iter().map(|(_, overlap)| overlap).sum();
+    if total <= 0.0 {
+        return Vec::new();
+    }
+    overlaps
+        .into_iter()
+        .map(|(h3_index, overlap)| (h3_index, overlap / total))
+        .collect()
+}
+
+/// Returns every cell overlapping `pixel` paired with the area of the
+/// overlap in square degrees.
+fn pixel_overlaps(pixel: &Polygon, resolution: u8) -> Vec<(u64, f64)> {
+    // Cells with a centroid inside the pixel, plus the cells
+    // containing points along its boundary, plus their immediate
+    // neighbors, cover every cell which partially overlaps the pixel.
+    let mut seeds: Vec<u64> = h3ron::polygon_to_cells(pixel, resolution)
+        .unwrap()
+        .iter()
+        .map(|hex| *hex)
+        .collect();
+    for point in boundary_points(pixel, resolution) {
+        seeds.push(*H3Cell::from_coordinate(point.0, resolution).unwrap());
+    }
+    let mut candidates = Vec::with_capacity(seeds.len() * 7);
+    for seed in seeds {
+        candidates.extend(
+            H3Cell::from_h3index(seed)
+                .grid_disk(1)
+                .unwrap()
+                .iter()
+                .map(|hex| *hex),
+        );
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let center_lon = pixel.centroid().unwrap().x();
+    candidates
+        .into_iter()
+        .filter_map(|h3_index| {
+            let hex_poly = cell_polygon(h3_index, center_lon);
+            let overlap = pixel.intersection(&hex_poly).unsigned_area();
+            (overlap > 0.0).then_some((h3_index, overlap))
+        })
+        .collect()
+}
+
+/// Returns points along the boundary of `pixel` no further apart than
+/// an H3 edge at `resolution`.
+///
+/// Near the poles pixels are narrow slivers which may contain no cell
+/// centroid, so their corners alone don't reach every cell along
+/// their long edges.
+fn boundary_points(pixel: &Polygon, resolution: u8) -> Vec<Point> {
+    let edge_km = H3_AVG_EDGE_LEN_KM[resolution as usize];
+    let mut points = Vec::new();
+    for line in pixel.exterior().lines() {
+        let (dx, dy) = (line.dx(), line.dy());
+        let mid_lat = line.start.y + dy / 2.0;
+        let len_km = (dx * mid_lat.to_radians().cos()).hypot(dy) * KM_PER_DEG;
+        let steps = (len_km / edge_km).ceil().max(1.0) as usize;
+        for step in 0..steps {
+            let t = step as f64 / steps as f64;
+            points.push(Point::new(line.start.x + t * dx, line.start.y + t * dy));
+        }
+    }
+    points
+}
+
+/// Returns the boundary of a cell in degrees, with longitudes unwrapped
+/// to within 180° of `center_lon`.
+///
+/// Cells straddling the antimeridian would otherwise span the whole
+/// globe. A cell containing a pole has no such unwrapping, so it is
+/// closed along the pole instead, repeated to cover 360° either side
+/// of its first vertex.
+pub(crate) fn cell_polygon(h3_index: u64, center_lon: f64) -> Polygon {
+    let boundary = H3Cell::from_h3index(h3_index).to_polygon().unwrap();
+    let mut vertices: Vec<_> = boundary.exterior().coords().copied().collect();
+    // The ring is closed, drop the repeated vertex.
+    vertices.pop();
+
+    // Unwrap each vertex relative to the previous one, starting within
+    // 180° of the pixel.
+    let mut prev = wrap_lon(vertices[0].x - center_lon) + center_lon;
+    vertices[0].x = prev;
+    for vertex in vertices.iter_mut().skip(1) {
+        vertex.x = prev + wrap_lon(vertex.x - prev);
+        prev = vertex.x;
+    }
+    let first = vertices[0];
+    let winding = prev + wrap_lon(first.x - prev) - first.x;
+    if winding.abs() < 180.0 {
+        return Polygon::new(LineString::from(vertices), vec![]);
+    }
+
+    let pole = if vertices.iter().map(|v| v.y).sum::<f64>() > 0.0 {
+        90.0
+    } else {
+        -90.0
+    };
+    let mut ring: Vec<_> = vertices
+        .iter()
+        .map(|v| coord! {x: v.x - winding, y: v.y})
+        .chain(vertices.iter().copied())
+        .collect();
+    ring.push(coord! {x: first.x + winding, y: first.y});
+    ring.push(coord! {x: first.x + winding, y: pole});
+    ring.push(coord! {x: first.x - winding, y: pole});
+    Polygon::new(LineString::from(ring), vec![])
+}
+
+/// Wraps a longitude difference into [-180, 180).
+fn wrap_lon(delta: f64) -> f64 {
+    (delta + 180.0).rem_euclid(360.0) - 180.0
 }
 
-pub fn gen_to_disk(src: GpwAscii, dst: &mut impl Write) {
-    let (tx, rx) = std::sync::mpsc::channel::<(Vec<u64>, f32)>();
+/// Population totals observed while transforming a dataset.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    /// Sum of every value read from the source.
+    pub source: f64,
+    /// Sum of every value written to the destination.
+    pub written: f64,
+    /// Sum of every value dropped by a clip polygon.
+    pub clipped: f64,
+}
+
+impl Totals {
+    /// Returns `|written - expected| / expected`, where `expected` is
+    /// the source total less anything clipped.
+    pub fn relative_error(&self) -> f64 {
+        let expected = self.source - self.clipped;
+        if expected == self.written {
+            0.0
+        } else {
+            ((self.written - expected) / expected).abs()
+        }
+    }
+
+    /// Returns `true` if the relative error is above `tolerance` or
+    /// isn't a number, as when a total is NaN.
+    pub fn exceeds(&self, tolerance: f64) -> bool {
+        let error = self.relative_error();
+        error.is_nan() || error > tolerance
+    }
+}
 
-    let handle = std::thread::spawn(move || {
-        let header = &src.header;
-        let data = &src.data;
-        data.into_par_iter()
-            .enumerate()
-            .for_each_with(tx, |tx, (row_idx, row)| {
+/// Number of raster rows tessellated in parallel at a time.
+const ROWS_PER_CHUNK: usize = 64;
+
+/// Tessellates every pixel of `raster` and writes the resulting (H3
+/// index, value) records to `dst`.
+///
+/// Rows are consumed in chunks so only a small window of the raster
+/// is held in memory at once. Contributions to the same cell are
+/// summed in `cells`, so each cell is written once, sorted by H3
+/// index. Contributions are summed in raster order, so the output is
+/// identical regardless of the number of threads.
+///
+/// Cells outside `clip`'s polygon are dropped rather than written.
+pub fn gen_to_disk<R: Raster + ?Sized, W: Write + Seek>(
+    raster: &mut R,
+    resolution: u8,
+    weighting: Weighting,
+    clip: Option<&Clip>,
+    mut cells: CellAccumulator,
+    dst: &mut H3TessWriter<W>,
+) -> Result<Totals, GpwError> {
+    let header = &raster.header().clone();
+    let mut totals = Totals::default();
+    let mut chunk: Vec<Row> = Vec::with_capacity(ROWS_PER_CHUNK);
+    loop {
+        chunk.clear();
+        for row in (&mut *raster).take(ROWS_PER_CHUNK) {
+            chunk.push(row?);
+        }
+        if chunk.is_empty() {
+            break;
+        }
+        totals.source += chunk
+            .iter()
+            .flat_map(|(_row_idx, row)| row.iter().flatten())
+            .map(|val| f64::from(*val))
+            .sum::<f64>();
+
+        let tessellated: Vec<Vec<(u64, f32)>> = chunk
+            .par_iter()
+            .flat_map(|(row_idx, row)| {
                 row.par_iter()
                     .enumerate()
-                    .for_each_with(tx.clone(), |tx, (col_idx, sample)| {
-                        if let Some(val) = sample {
-                            let h3_indicies = tessalate_grid(header, row_idx, col_idx);
-                            tx.send((h3_indicies, *val)).unwrap();
-                        }
+                    .filter_map(move |(col_idx, sample)| {
+                        sample.map(|val| {
+                            tessalate_grid(header, resolution, weighting, *row_idx, col_idx)
+                                .into_iter()
+                                .map(|(h3_index, weight)| {
+                                    (h3_index, (f64::from(val) * weight) as f32)
+                                })
+                                .collect::<Vec<(u64, f32)>>()
+                        })
                     })
             })
-    });
-
-    while let Ok((h3_indicies, val)) = rx.recv() {
-        let scaled_val = val / h3_indicies.len() as f32;
-        let scaled_val_bytes = scaled_val.to_le_bytes();
-        for h3_index in h3_indicies {
-            dst.write_all(&h3_index.to_le_bytes()).unwrap();
-            dst.write_all(&scaled_val_bytes).unwrap();
+            .collect();
+
+        for (h3_index, scaled_val) in tessellated.into_iter().flatten() {
+            cells.add(h3_index, f64::from(scaled_val))?;
         }
     }
-    handle.join().unwrap();
+    cells.finish(|h3_index, val| {
+        let val = val as f32;
+        if clip.is_some_and(|clip| !clip.contains_cell(h3_index)) {
+            totals.clipped += f64::from(val);
+            return Ok(());
+        }
+        totals.written += f64::from(val);
+        Ok(dst.write(h3_index, val)?)
+    })?;
+    Ok(totals)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{
-        fs::File,
-        io::{BufReader, BufWriter, Cursor},
+    use crate::{
+        accumulate::DEFAULT_MAX_CELLS,
+        error::Location,
+        gpwascii::{GpwAscii, GpwAsciiRows},
+        raster::NegativePolicy,
+        test_util::H3TessFixture,
     };
+    use geo::polygon;
+    use std::io::{BufRead, BufReader, Cursor};
 
     #[test]
     fn test_parse_header() {
@@ -83,6 +368,46 @@ NODATA_value  -9999
         GpwAsciiHeader::parse(&mut rdr).unwrap();
     }
 
+    #[test]
+    fn test_parse_header_variants() {
+        let header = r#"NCOLS 4
+nrows 2
+XLLCENTER -179.5
+yllcenter 0.25
+dx 1.0
+dy 0.5
+1 2 3 4
+"#;
+        let mut rdr = BufReader::new(Cursor::new(header));
+        let header = GpwAsciiHeader::parse(&mut rdr).unwrap();
+        assert_eq!(
+            header,
+            GpwAsciiHeader {
+                ncols: 4,
+                nrows: 2,
+                xllcorner: -180.0,
+                yllcorner: 0.0,
+                dx: 1.0,
+                dy: 0.5,
+                nodata_value: Some(-9999.0),
+            }
+        );
+        let mut first_row = String::new();
+        rdr.read_line(&mut first_row).unwrap();
+        assert_eq!(first_row, "1 2 3 4\n");
+
+        let header = r#"cellsize 0.5
+nodata_value -1
+xllcorner 10
+yllcorner 20
+nrows 2
+ncols 4
+"#;
+        let header = GpwAsciiHeader::parse(&mut BufReader::new(Cursor::new(header))).unwrap();
+        assert_eq!((header.dx, header.dy), (0.5, 0.5));
+        assert_eq!(header.nodata_value, Some(-1.0));
+    }
+
     #[test]
     fn test_parse() {
         let file = r#"ncols         4
@@ -100,6 +425,104 @@ NODATA_value  -9999
         GpwAscii::parse(&mut rdr).unwrap();
     }
 
+    #[test]
+    fn test_parse_errors() {
+        let header = r#"ncols         4
+nrows         2
+xllcorner     -180
+yllcorner     -4.2632564145606e-14
+cellsize      0.0083333333333333
+NODATA_value  -9999
+"#;
+        let parse = |data: &str| {
+            let file = format!("{}{}", header, data);
+            GpwAsciiRows::new(BufReader::new(Cursor::new(file)))
+                .unwrap()
+                .with_filename("test.asc")
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let location = |line, column| Location {
+            file: Some("test.asc".to_string()),
+            line,
+            column,
+        };
+
+        assert!(parse("1 2 3 4\n1 2 3 4\n\n").is_ok());
+        assert!(matches!(
+            parse("1 2 3 4\n1 2 3\n"),
+            Err(GpwError::ShortRow { location: l, expected: 4, found: 3 }) if l == location(8, 4)
+        ));
+        assert!(matches!(
+            parse("1 2 3 4 5\n1 2 3 4\n"),
+            Err(GpwError::LongRow { location: l, expected: 4, found: 5 }) if l == location(7, 5)
+        ));
+        assert!(matches!(
+            parse("1 2 3 4\n"),
+            Err(GpwError::MissingRows {
+                expected: 2,
+                found: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("1 2 3 4\n1 2 3 4\n1 2 3 4\n"),
+            Err(GpwError::TrailingData { location: l }) if l == location(9, 1)
+        ));
+        assert!(matches!(
+            parse("1 2 3 4\n1 x 3 4\n"),
+            Err(GpwError::InvalidCell { location: l, value }) if l == location(8, 2) && value == "x"
+        ));
+    }
+
+    #[test]
+    fn test_nodata_and_negatives() {
+        let file = r#"ncols         4
+nrows         1
+xllcorner     -180
+yllcorner     0
+cellsize      0.0083333333333333
+NODATA_value  -3.4028234663852886e+38
+-3.40282346639e+38 -3.4028234663852886e+38 -1.5 2
+"#;
+        let parse = |policy| {
+            let mut rows = GpwAsciiRows::new(BufReader::new(Cursor::new(file)))
+                .unwrap()
+                .with_negative_policy(policy);
+            let row = rows.next().unwrap().map(|(_row_idx, row)| row);
+            (row, rows.suspicious)
+        };
+        assert!(matches!(
+            parse(NegativePolicy::Error),
+            (Err(GpwError::NegativeCell { value, .. }), 0) if value == -1.5
+        ));
+        assert!(matches!(
+            parse(NegativePolicy::Drop),
+            (Ok(row), 1) if row == vec![None, None, None, Some(2.0)]
+        ));
+        assert!(matches!(
+            parse(NegativePolicy::Clamp),
+            (Ok(row), 1) if row == vec![None, None, Some(0.0), Some(2.0)]
+        ));
+    }
+
+    #[test]
+    fn test_totals_exceeds() {
+        let totals = Totals {
+            source: 10.0,
+            written: 9.0,
+            clipped: 0.0,
+        };
+        assert!(totals.exceeds(0.01));
+        assert!(!totals.exceeds(0.1));
+        // A NaN sample makes every total NaN, which must not pass.
+        let nan = Totals {
+            source: 10.0 + f64::from(f32::NAN),
+            written: 10.0 + f64::from(f32::NAN),
+            clipped: 0.0,
+        };
+        assert!(nan.exceeds(0.1));
+    }
+
     #[test]
     fn test_gen_to_disk() {
         let file = r#"ncols         4
@@ -111,11 +534,197 @@ NODATA_value  -9999
 -9999 -9999 -9999 -9999
 -9999 -9999 -9999 -9999
 -9999 -9999 -9999 -9999
--9999 -9999 0.123 -9999
+-9999 -9999 0.123 4.56
 "#;
-        let mut rdr = BufReader::new(Cursor::new(file));
-        let data = GpwAscii::parse(&mut rdr).unwrap();
-        let mut dst = BufWriter::new(File::create("/Users/jay/he/gpw/out.indicies").unwrap());
-        gen_to_disk(data, &mut dst);
+        for weighting in [Weighting::Even, Weighting::Area] {
+            let mut rows = GpwAsciiRows::new(BufReader::new(Cursor::new(file))).unwrap();
+            let mut out = H3TessFixture::new("gen_to_disk", 10);
+            let cells = out.cells(DEFAULT_MAX_CELLS);
+            let totals = gen_to_disk(&mut rows, 10, weighting, None, cells, &mut out.dst).unwrap();
+            let records = out.records();
+            assert!(!records.is_empty());
+            // Each cell is written once, in ascending order.
+            assert!(records.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            assert!(totals.relative_error() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_gen_to_disk_clip() {
+        let file = "ncols 2\nnrows 1\nxllcorner 10\nyllcorner 45\ncellsize 0.01\n1000 2000\n";
+        let polygon = polygon![
+            (x: 9.0, y: 44.0),
+            (x: 10.01, y: 44.0),
+            (x: 10.01, y: 46.0),
+            (x: 9.0, y: 46.0),
+        ];
+        let clip = Clip::from_polygon(geo::MultiPolygon::new(vec![polygon])).unwrap();
+        let mut rows = GpwAsciiRows::new(Cursor::new(file)).unwrap();
+        let mut out = H3TessFixture::new("gen_to_disk_clip", 10);
+        let cells = out.cells(DEFAULT_MAX_CELLS);
+        let totals = gen_to_disk(
+            &mut rows,
+            10,
+            Weighting::Area,
+            Some(&clip),
+            cells,
+            &mut out.dst,
+        )
+        .unwrap();
+        let records = out.records();
+        assert!(records
+            .iter()
+            .all(|(h3_index, _)| clip.contains_cell(*h3_index)));
+        // Roughly the western pixel is kept.
+        assert!((totals.written - 1000.0).abs() < 200.0);
+        assert!((totals.clipped - 2000.0).abs() < 200.0);
+        assert!(totals.relative_error() < 1e-6);
+    }
+
+    #[test]
+    fn test_gen_to_disk_deterministic() {
+        let file = r#"ncols         4
+nrows         3
+xllcorner     10
+yllcorner     45
+cellsize      0.0083333333333333
+NODATA_value  -9999
+1.5 2.25 -9999 7
+0.5 3 4.75 1
+-9999 9.5 2 0.125
+"#;
+        let tessellate = |threads: usize, max_cells: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let mut rows = GpwAsciiRows::new(BufReader::new(Cursor::new(file))).unwrap();
+                let name = format!("deterministic_{}_{}", threads, max_cells);
+                let mut out = H3TessFixture::new(&name, 10);
+                let cells = out.cells(max_cells);
+                gen_to_disk(&mut rows, 10, Weighting::Area, None, cells, &mut out.dst).unwrap();
+                out.finish()
+            })
+        };
+        let expected = tessellate(1, usize::MAX);
+        assert_eq!(tessellate(4, usize::MAX), expected);
+        assert_eq!(tessellate(4, usize::MAX), expected);
+        assert_eq!(tessellate(4, 64), tessellate(1, 64));
+    }
+
+    #[test]
+    fn test_fallback_to_centroid_cell() {
+        let header = GpwAsciiHeader {
+            ncols: 4,
+            nrows: 4,
+            xllcorner: -180.0,
+            yllcorner: 0.0,
+            dx: 0.0083333333333333,
+            dy: 0.0083333333333333,
+            nodata_value: Some(-9999.0),
+        };
+        for weighting in [Weighting::Even, Weighting::Area] {
+            let cells = tessalate_grid(&header, 4, weighting, 3, 2);
+            assert_eq!(cells.len(), 1);
+            assert_eq!(cells[0].1, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_area_weights() {
+        let header = GpwAsciiHeader {
+            ncols: 4,
+            nrows: 4,
+            xllcorner: -180.0,
+            yllcorner: 0.0,
+            dx: 0.0083333333333333,
+            dy: 0.0083333333333333,
+            nodata_value: Some(-9999.0),
+        };
+        for resolution in [7, 10] {
+            let even = tessalate_grid(&header, resolution, Weighting::Even, 3, 2);
+            let area = tessalate_grid(&header, resolution, Weighting::Area, 3, 2);
+            let total: f64 = area.iter().map(|(_, weight)| weight).sum();
+            assert!((total - 1.0).abs() < 1e-9);
+            assert!(area.len() >= even.len());
+        }
+    }
+
+    /// Returns the header of a 30 arc-second GPW tile with its lower
+    /// left corner at (`xllcorner`, `yllcorner`).
+    fn gpw_tile(xllcorner: f64, yllcorner: f64) -> GpwAsciiHeader {
+        GpwAsciiHeader {
+            ncols: 10800,
+            nrows: 10800,
+            xllcorner,
+            yllcorner,
+            dx: 0.0083333333333333,
+            dy: 0.0083333333333333,
+            nodata_value: Some(-9999.0),
+        }
+    }
+
+    #[test]
+    fn test_tile_edge_pixels() {
+        let tile_1 = gpw_tile(-180.0, -4.2632564145606e-14);
+        let tile_4 = gpw_tile(90.0, -4.2632564145606e-14);
+        let tile_5 = gpw_tile(-180.0, -90.0);
+        let tile_8 = gpw_tile(90.0, -90.0);
+        // Rows at 66°N through Chukotka and 17°S through Fiji.
+        let (chukotka, fiji) = (2880, 2040);
+        let pixels = [
+            (&tile_1, 0, 0),
+            (&tile_1, chukotka, 0),
+            (&tile_4, 0, 10799),
+            (&tile_4, chukotka, 10799),
+            (&tile_5, fiji, 0),
+            (&tile_5, 10799, 0),
+            (&tile_8, fiji, 10799),
+            (&tile_8, 10799, 10799),
+        ];
+        for (header, row, col) in pixels {
+            let pixel = pixel_polygon(header, row, col);
+            // Cells tile the pixel exactly, even where they wrap around
+            // the antimeridian or contain a pole.
+            let overlaps = pixel_overlaps(&pixel, 10);
+            let covered: f64 = overlaps.iter().map(|(_, overlap)| overlap).sum();
+            let area = pixel.unsigned_area();
+            assert!(
+                ((covered - area) / area).abs() < 1e-6,
+                "pixel ({}, {}) at {}: covered {} of {}",
+                row,
+                col,
+                header.xllcorner,
+                covered,
+                area
+            );
+            for weighting in [Weighting::Even, Weighting::Area] {
+                let weights = tessalate_grid(header, 10, weighting, row, col);
+                let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+                assert!((total - 1.0).abs() < 1e-9);
+            }
+        }
+
+        // Cells straddling the antimeridian are shared by the pixels on
+        // either side.
+        for (west, east, row) in [(&tile_1, &tile_4, chukotka), (&tile_5, &tile_8, fiji)] {
+            let west = tessalate_grid(west, 10, Weighting::Area, row, 0);
+            let east = tessalate_grid(east, 10, Weighting::Area, row, 10799);
+            assert!(west
+                .iter()
+                .any(|(h3_index, _)| east.iter().any(|(other, _)| other == h3_index)));
+        }
+    }
+
+    #[test]
+    fn test_cells_coarser_than_pixels() {
+        let header = GpwAsciiHeader {
+            dx: 0.0083333333333333,
+            dy: 0.0083333333333333,
+            ..Default::default()
+        };
+        assert!(!cells_coarser_than_pixels(&header, 10));
+        assert!(cells_coarser_than_pixels(&header, 7));
     }
 }
@@ -0,0 +1,472 @@
+use crate::{
+    error::{GpwError, Location},
+    gpwascii::GpwAsciiHeader,
+    raster::{classify_sample, NegativePolicy, Raster, Row},
+    source::Checksum,
+};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Seek},
+    ops::Range,
+};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    tags::Tag,
+    ColorType,
+};
+
+// GeoTIFF and GDAL tags describing how pixels map to coordinates.
+const MODEL_PIXEL_SCALE: u16 = 33550;
+const MODEL_TIEPOINT: u16 = 33922;
+const MODEL_TRANSFORMATION: u16 = 34264;
+const GEO_KEY_DIRECTORY: u16 = 34735;
+const GDAL_NODATA: u16 = 42113;
+
+/// `GTRasterTypeGeoKey` and its `RasterPixelIsPoint` value.
+const GT_RASTER_TYPE_GEO_KEY: u32 = 1025;
+const RASTER_PIXEL_IS_POINT: u32 = 2;
+
+/// Streaming reader which decodes a single-band GeoTIFF one band of
+/// strips or tiles at a time.
+///
+/// Both striped and tiled layouts, and any compression supported by
+/// the `tiff` crate (including DEFLATE and LZW), are read.
+pub struct GeoTiffRows<R: Read + Seek> {
+    pub header: GpwAsciiHeader,
+    pub filename: Option<String>,
+    /// Number of negative or non-finite samples dropped or clamped so
+    /// far.
+    pub suspicious: usize,
+    negative_policy: NegativePolicy,
+    /// Columns whose chunks are decoded.
+    columns: Range<usize>,
+    decoder: Decoder<R>,
+    chunk_width: usize,
+    chunk_height: usize,
+    chunks_across: usize,
+    /// Index of the next row of chunks to decode.
+    next_chunk_row: usize,
+    /// Decoded rows not yet yielded.
+    pending: VecDeque<Row>,
+    done: bool,
+    /// A second handle on the file, hashed by `read_remainder`.
+    checksum: Option<(File, Checksum)>,
+}
+
+impl<R: Read + Seek> GeoTiffRows<R> {
+    /// Reads the TIFF directory and geotransform tags from `rdr`.
+    pub fn new(rdr: R) -> Result<Self, GpwError> {
+        let mut decoder = Decoder::new(rdr)?;
+        if !matches!(decoder.colortype()?, ColorType::Gray(_)) {
+            return Err(GpwError::Parse(
+                "GeoTIFF must have a single band",
+                Some(Box::new(decoder.colortype()?)),
+            ));
+        }
+        let header = read_header(&mut decoder)?;
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        let (chunk_width, chunk_height) = (chunk_width as usize, chunk_height as usize);
+        let chunks_across = header.ncols.div_ceil(chunk_width);
+        Ok(Self {
+            columns: 0..header.ncols,
+            header,
+            filename: None,
+            suspicious: 0,
+            negative_policy: NegativePolicy::Error,
+            decoder,
+            chunk_width,
+            chunk_height,
+            chunks_across,
+            next_chunk_row: 0,
+            pending: VecDeque::new(),
+            done: false,
+            checksum: None,
+        })
+    }
+
+    /// Sets the file name reported in errors.
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// Sets how negative samples are handled.
+    pub fn with_negative_policy(mut self, policy: NegativePolicy) -> Self {
+        self.negative_policy = policy;
+        self
+    }
+
+    /// Adds the contents of `file`, the same GeoTIFF opened again, to
+    /// `checksum` when the remainder is read. Chunks are decoded out of
+    /// file order, so the decoder's reads can't be hashed as they go.
+    pub fn with_checksum(mut self, file: File, checksum: Checksum) -> Self {
+        self.checksum = Some((file, checksum));
+        self
+    }
+
+    /// Decodes the next row of chunks into `self.pending`.
+    fn decode_chunk_row(&mut self) -> Result<(), GpwError> {
+        let first_row = self.next_chunk_row * self.chunk_height;
+        let band_height = self.chunk_height.min(self.header.nrows - first_row);
+        let mut band = vec![vec![None; self.header.ncols]; band_height];
+
+        for chunk_col in 0..self.chunks_across {
+            let first_col = chunk_col * self.chunk_width;
+            if first_col >= self.columns.end || first_col + self.chunk_width <= self.columns.start {
+                continue;
+            }
+            let chunk_index = (self.next_chunk_row * self.chunks_across + chunk_col) as u32;
+            let (data_width, data_height) = self.decoder.chunk_data_dimensions(chunk_index);
+            let data = samples_to_f32(self.decoder.read_chunk(chunk_index)?)?;
+            // Edge tiles may be padded out to the full tile size.
+            let stride = data.len() / data_height as usize;
+            for (line, row) in band.iter_mut().enumerate().take(data_height as usize) {
+                let samples = &data[line * stride..line * stride + data_width as usize];
+                for (offset, val) in samples.iter().enumerate() {
+                    row[first_col + offset] = classify_sample(
+                        &self.header,
+                        self.negative_policy,
+                        &mut self.suspicious,
+                        *val,
+                    )
+                    .map_err(|()| GpwError::NegativeCell {
+                        location: Location {
+                            file: self.filename.clone(),
+                            line: first_row + line + 1,
+                            column: first_col + offset + 1,
+                        },
+                        value: *val,
+                    })?;
+                }
+            }
+        }
+
+        self.pending.extend(
+            band.into_iter()
+                .enumerate()
+                .map(|(line, row)| (first_row + line, row)),
+        );
+        self.next_chunk_row += 1;
+        Ok(())
+    }
+
+    fn parse_row(&mut self) -> Result<Option<Row>, GpwError> {
+        if self.pending.is_empty() && self.next_chunk_row * self.chunk_height < self.header.nrows {
+            self.decode_chunk_row()?;
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+impl<R: Read + Seek> Iterator for GeoTiffRows<R> {
+    type Item = Result<Row, GpwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let row = self.parse_row();
+        self.done = !matches!(row, Ok(Some(_)));
+        row.transpose()
+    }
+}
+
+impl<R: Read + Seek> Raster for GeoTiffRows<R> {
+    fn header(&self) -> &GpwAsciiHeader {
+        &self.header
+    }
+
+    fn suspicious(&self) -> usize {
+        self.suspicious
+    }
+
+    fn skip_rows(&mut self, mut n: usize) -> Result<(), GpwError> {
+        while n > 0 {
+            if self.pending.pop_front().is_some() {
+                n -= 1;
+                continue;
+            }
+            let first_row = self.next_chunk_row * self.chunk_height;
+            if first_row >= self.header.nrows {
+                break;
+            }
+            // Whole rows of chunks are skipped without decoding them.
+            let band_height = self.chunk_height.min(self.header.nrows - first_row);
+            if n >= band_height {
+                self.next_chunk_row += 1;
+                n -= band_height;
+            } else {
+                self.decode_chunk_row()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restrict_columns(&mut self, cols: Range<usize>) {
+        self.columns = cols;
+    }
+
+    fn read_remainder(&mut self) -> Result<(), GpwError> {
+        if let Some((file, checksum)) = self.checksum.take() {
+            io::copy(&mut checksum.reader(file), &mut io::sink())?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a corner-registered header from the image dimensions and
+/// GeoTIFF tags.
+fn read_header<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<GpwAsciiHeader, GpwError> {
+    let (width, height) = decoder.dimensions()?;
+    let (ncols, nrows) = (width as usize, height as usize);
+
+    // Model coordinates of the top-left corner of the top-left pixel
+    // and the pixel size.
+    let (mut left, mut top, dx, dy) = if let (Some(scale), Some(tiepoint)) = (
+        optional_f64_vec(decoder, MODEL_PIXEL_SCALE)?,
+        optional_f64_vec(decoder, MODEL_TIEPOINT)?,
+    ) {
+        if scale.len() < 2 || tiepoint.len() < 6 {
+            return Err(GpwError::Parse("GeoTIFF tiepoint", None));
+        }
+        let (dx, dy) = (scale[0], scale[1]);
+        let (i, j, x, y) = (tiepoint[0], tiepoint[1], tiepoint[3], tiepoint[4]);
+        (x - i * dx, y + j * dy, dx, dy)
+    } else if let Some(transform) = optional_f64_vec(decoder, MODEL_TRANSFORMATION)? {
+        if transform.len() < 8 || transform[1] != 0.0 || transform[4] != 0.0 {
+            return Err(GpwError::Parse(
+                "GeoTIFF transformation must not be rotated",
+                None,
+            ));
+        }
+        (transform[3], transform[7], transform[0], -transform[5])
+    } else {
+        return Err(GpwError::Parse("GeoTIFF georeferencing", None));
+    };
+
+    if is_pixel_is_point(decoder)? {
+        left -= dx / 2.0;
+        top += dy / 2.0;
+    }
+
+    let nodata_value = match decoder.find_tag(Tag::from_u16_exhaustive(GDAL_NODATA))? {
+        Some(value) => {
+            let nodata = value.into_string()?;
+            Some(
+                nodata
+                    .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                    .parse::<f64>()
+                    .map_err(|e| ("GDAL_NODATA", e))?,
+            )
+        }
+        None => None,
+    };
+
+    Ok(GpwAsciiHeader {
+        ncols,
+        nrows,
+        xllcorner: left,
+        yllcorner: top - dy * nrows as f64,
+        dx,
+        dy,
+        nodata_value,
+    })
+}
+
+/// Returns `true` if the GeoKey directory registers coordinates at
+/// pixel centers rather than corners.
+fn is_pixel_is_point<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<bool, GpwError> {
+    let keys = match decoder.find_tag(Tag::from_u16_exhaustive(GEO_KEY_DIRECTORY))? {
+        Some(value) => value.into_u32_vec()?,
+        None => return Ok(false),
+    };
+    // A four value header followed by (key, location, count, value)
+    // entries.
+    Ok(keys
+        .chunks_exact(4)
+        .skip(1)
+        .any(|entry| entry[0] == GT_RASTER_TYPE_GEO_KEY && entry[3] == RASTER_PIXEL_IS_POINT))
+}
+
+fn optional_f64_vec<R: Read + Seek>(
+    decoder: &mut Decoder<R>,
+    tag: u16,
+) -> Result<Option<Vec<f64>>, GpwError> {
+    match decoder.find_tag(Tag::from_u16_exhaustive(tag))? {
+        Some(value) => Ok(Some(value.into_f64_vec()?)),
+        None => Ok(None),
+    }
+}
+
+fn samples_to_f32(samples: DecodingResult) -> Result<Vec<f32>, GpwError> {
+    match samples {
+        DecodingResult::F32(samples) => Ok(samples),
+        DecodingResult::F64(samples) => Ok(samples.into_iter().map(|v| v as f32).collect()),
+        DecodingResult::U8(samples) => Ok(samples.into_iter().map(f32::from).collect()),
+        DecodingResult::U16(samples) => Ok(samples.into_iter().map(f32::from).collect()),
+        DecodingResult::U32(samples) => Ok(samples.into_iter().map(|v| v as f32).collect()),
+        DecodingResult::I8(samples) => Ok(samples.into_iter().map(f32::from).collect()),
+        DecodingResult::I16(samples) => Ok(samples.into_iter().map(f32::from).collect()),
+        DecodingResult::I32(samples) => Ok(samples.into_iter().map(|v| v as f32).collect()),
+        _ => Err(GpwError::Parse("GeoTIFF sample format", None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression as ZlibCompression};
+    use std::io::{Cursor, Seek, Write};
+    use tiff::encoder::{
+        colortype::Gray32Float, Compression, DirectoryEncoder, TiffEncoder, TiffKindStandard,
+    };
+
+    /// Writes a 0.5° by 0.25° grid with its top-left corner at
+    /// (-180, 1), and `nodata` as its NODATA value.
+    fn write_geo_tags<W: Write + Seek>(
+        encoder: &mut DirectoryEncoder<'_, W, TiffKindStandard>,
+        nodata: &str,
+    ) {
+        encoder
+            .write_tag(
+                Tag::from_u16_exhaustive(MODEL_PIXEL_SCALE),
+                &[0.5_f64, 0.25, 0.0][..],
+            )
+            .unwrap();
+        encoder
+            .write_tag(
+                Tag::from_u16_exhaustive(MODEL_TIEPOINT),
+                &[0.0_f64, 0.0, 0.0, -180.0, 1.0, 0.0][..],
+            )
+            .unwrap();
+        encoder
+            .write_tag(Tag::from_u16_exhaustive(GDAL_NODATA), nodata)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_geotiff_rows() {
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut tiff = TiffEncoder::new(&mut buf).unwrap();
+            let mut image = tiff.new_image::<Gray32Float>(3, 2).unwrap();
+            write_geo_tags(image.encoder(), "-9999");
+            image
+                .write_data(&[1.0, -9999.0, 2.0, 3.0, 4.0, -9999.0])
+                .unwrap();
+        }
+        buf.set_position(0);
+
+        let rows = GeoTiffRows::new(buf).unwrap();
+        assert_eq!(
+            rows.header,
+            GpwAsciiHeader {
+                ncols: 3,
+                nrows: 2,
+                xllcorner: -180.0,
+                yllcorner: 0.5,
+                dx: 0.5,
+                dy: 0.25,
+                nodata_value: Some(-9999.0),
+            }
+        );
+        let rows = rows.collect::<Result<Vec<Row>, _>>().unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (0, vec![Some(1.0), None, Some(2.0)]),
+                (1, vec![Some(3.0), Some(4.0), None]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lzw_strips_with_nan_nodata() {
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut tiff = TiffEncoder::new(&mut buf)
+                .unwrap()
+                .with_compression(Compression::Lzw);
+            let mut image = tiff.new_image::<Gray32Float>(3, 2).unwrap();
+            image.rows_per_strip(1).unwrap();
+            write_geo_tags(image.encoder(), "nan");
+            image
+                .write_data(&[1.0, f32::NAN, 2.0, f32::NAN, 4.0, 5.0])
+                .unwrap();
+        }
+        buf.set_position(0);
+
+        let mut rows = GeoTiffRows::new(buf).unwrap();
+        assert!(rows.header.nodata_value.is_some_and(f64::is_nan));
+        assert_eq!(
+            rows.by_ref().collect::<Result<Vec<Row>, _>>().unwrap(),
+            vec![
+                (0, vec![Some(1.0), None, Some(2.0)]),
+                (1, vec![None, Some(4.0), Some(5.0)]),
+            ]
+        );
+        assert_eq!(rows.suspicious, 0);
+    }
+
+    #[test]
+    fn test_deflate_tiles() {
+        // Two by two 16x16 tiles, with the right and bottom ones
+        // partly outside the image.
+        let (ncols, nrows, tile) = (20, 18, 16);
+        let sample = |row: usize, col: usize| (row * 100 + col) as f32;
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut tiff = TiffEncoder::new(&mut buf).unwrap();
+            let mut dir = tiff.image_directory().unwrap();
+            let mut offsets = Vec::new();
+            let mut byte_counts = Vec::new();
+            for tile_row in 0..2 {
+                for tile_col in 0..2 {
+                    let mut zlib = ZlibEncoder::new(Vec::new(), ZlibCompression::default());
+                    for row in tile_row * tile..(tile_row + 1) * tile {
+                        for col in tile_col * tile..(tile_col + 1) * tile {
+                            zlib.write_all(&sample(row, col).to_ne_bytes()).unwrap();
+                        }
+                    }
+                    let data = zlib.finish().unwrap();
+                    offsets.push(dir.write_data(&data[..]).unwrap() as u32);
+                    byte_counts.push(data.len() as u32);
+                }
+            }
+            dir.write_tag(Tag::ImageWidth, ncols as u32).unwrap();
+            dir.write_tag(Tag::ImageLength, nrows as u32).unwrap();
+            dir.write_tag(Tag::BitsPerSample, 32_u16).unwrap();
+            dir.write_tag(Tag::Compression, 8_u16).unwrap();
+            dir.write_tag(Tag::PhotometricInterpretation, 1_u16)
+                .unwrap();
+            dir.write_tag(Tag::SamplesPerPixel, 1_u16).unwrap();
+            dir.write_tag(Tag::SampleFormat, 3_u16).unwrap();
+            dir.write_tag(Tag::TileWidth, tile as u32).unwrap();
+            dir.write_tag(Tag::TileLength, tile as u32).unwrap();
+            dir.write_tag(Tag::TileOffsets, &offsets[..]).unwrap();
+            dir.write_tag(Tag::TileByteCounts, &byte_counts[..])
+                .unwrap();
+            write_geo_tags(&mut dir, "-9999");
+            dir.finish().unwrap();
+        }
+        buf.set_position(0);
+
+        let rows = GeoTiffRows::new(buf.clone()).unwrap();
+        assert_eq!((rows.header.ncols, rows.header.nrows), (ncols, nrows));
+        let rows = rows.collect::<Result<Vec<Row>, _>>().unwrap();
+        let expected: Vec<Row> = (0..nrows)
+            .map(|row| (row, (0..ncols).map(|col| Some(sample(row, col))).collect()))
+            .collect();
+        assert_eq!(rows, expected);
+
+        // Rows and columns of the bottom right tile only.
+        let mut rows = GeoTiffRows::new(buf).unwrap();
+        rows.restrict_columns(17..20);
+        rows.skip_rows(17).unwrap();
+        let (row_idx, row) = rows.next().unwrap().unwrap();
+        assert_eq!(row_idx, 17);
+        assert_eq!(row[17..], expected[17].1[17..]);
+        assert!(rows.next().is_none());
+    }
+}
@@ -1,5 +1,13 @@
-use crate::error::GpwError;
-use std::io::{BufRead, BufReader};
+use crate::{
+    error::{GpwError, Location},
+    raster::{classify_sample, NegativePolicy, Raster, Row},
+};
+use std::{
+    fmt::Debug,
+    io::{self, BufRead, Write},
+    ops::Range,
+    str::FromStr,
+};
 
 // $ head -n6    gpw_v4_population_count_rev11_2020_30_sec_1.asc
 // ncols         10800
@@ -8,112 +16,169 @@ use std::io::{BufRead, BufReader};
 // yllcorner     -4.2632564145606e-14
 // cellsize      0.0083333333333333
 // NODATA_value  -9999
+//
+// Other ESRI ASCII grids may list these keys in any order and case,
+// register the grid by the center of the lower-left pixel
+// (`xllcenter`/`yllcenter`), use non-square pixels (`dx`/`dy`), or
+// omit `NODATA_value`, which then defaults to -9999 as in the ESRI
+// specification. The parsed header is always normalized to corner
+// registration.
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct GpwAsciiHeader {
     pub ncols: usize,
     pub nrows: usize,
+    /// Longitude of the lower-left corner of the grid.
     pub xllcorner: f64,
+    /// Latitude of the lower-left corner of the grid.
     pub yllcorner: f64,
-    pub cellsize: f64,
-    pub nodata_value: String,
+    /// Pixel width in degrees.
+    pub dx: f64,
+    /// Pixel height in degrees.
+    pub dy: f64,
+    pub nodata_value: Option<f64>,
 }
 
+/// NODATA value of ESRI ASCII grids without a `NODATA_value` key.
+const DEFAULT_NODATA_VALUE: f64 = -9999.0;
+
 impl GpwAsciiHeader {
-    pub fn parse<R: std::io::Read>(rdr: &mut BufReader<R>) -> Result<Self, GpwError> {
+    pub fn parse<B: BufRead>(rdr: &mut B) -> Result<Self, GpwError> {
+        Self::parse_counted(rdr).map(|(header, _lines)| header)
+    }
+
+    /// Parses a header and returns it along with the number of lines
+    /// consumed.
+    ///
+    /// The header ends at the first line which does not start with a
+    /// key, leaving `rdr` positioned at the first data row.
+    fn parse_counted<B: BufRead>(rdr: &mut B) -> Result<(Self, usize), GpwError> {
         let mut ncols: Option<usize> = None;
         let mut nrows: Option<usize> = None;
         let mut xllcorner: Option<f64> = None;
         let mut yllcorner: Option<f64> = None;
+        let mut xllcenter: Option<f64> = None;
+        let mut yllcenter: Option<f64> = None;
         let mut cellsize: Option<f64> = None;
-        let mut nodata_value: Option<String> = None;
+        let mut dx: Option<f64> = None;
+        let mut dy: Option<f64> = None;
+        let mut nodata_value: Option<f64> = None;
 
-        for _ in 0..6 {
+        let mut lines = 0;
+        while starts_with_key(rdr)? {
             let mut line = String::new();
             rdr.read_line(&mut line)?;
+            lines += 1;
             let mut tokens = line.split_whitespace();
             if let Some(token) = tokens.next() {
-                match token {
-                    "ncols" => {
-                        ncols = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("ncols", None))?
-                                .parse::<usize>()
-                                .map_err(|e| ("ncols", e))?,
-                        );
-                    }
-                    "nrows" => {
-                        nrows = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("nrows", None))?
-                                .parse::<usize>()
-                                .map_err(|e| ("ncols", e))?,
-                        );
-                    }
-                    "xllcorner" => {
-                        xllcorner = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("xllcorner", None))?
-                                .parse::<f64>()
-                                .map_err(|e| ("xllcorner", e))?,
-                        );
-                    }
-                    "yllcorner" => {
-                        yllcorner = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("yllcorner", None))?
-                                .parse::<f64>()
-                                .map_err(|e| ("yllcorner", e))?,
-                        );
-                    }
-                    "cellsize" => {
-                        cellsize = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("cellsize", None))?
-                                .parse::<f64>()
-                                .map_err(|e| ("cellsize", e))?,
-                        );
-                    }
-                    "NODATA_value" => {
-                        nodata_value = Some(
-                            tokens
-                                .next()
-                                .ok_or(GpwError::Parse("NODATA_value", None))?
-                                .to_string(),
-                        );
-                    }
-                    unexpected_token => {
-                        Err(("unexpected header token", unexpected_token.to_string()))?
-                    }
+                let value = tokens.next();
+                match token.to_ascii_lowercase().as_str() {
+                    "ncols" => ncols = Some(parse_value("ncols", value)?),
+                    "nrows" => nrows = Some(parse_value("nrows", value)?),
+                    "xllcorner" => xllcorner = Some(parse_value("xllcorner", value)?),
+                    "yllcorner" => yllcorner = Some(parse_value("yllcorner", value)?),
+                    "xllcenter" => xllcenter = Some(parse_value("xllcenter", value)?),
+                    "yllcenter" => yllcenter = Some(parse_value("yllcenter", value)?),
+                    "cellsize" => cellsize = Some(parse_value("cellsize", value)?),
+                    "dx" => dx = Some(parse_value("dx", value)?),
+                    "dy" => dy = Some(parse_value("dy", value)?),
+                    "nodata_value" => nodata_value = Some(parse_value("NODATA_value", value)?),
+                    _ => Err(("unexpected header token", token.to_string()))?,
                 }
             }
         }
 
-        if let (
-            Some(ncols),
-            Some(nrows),
-            Some(xllcorner),
-            Some(yllcorner),
-            Some(cellsize),
-            Some(nodata_value),
-        ) = (ncols, nrows, xllcorner, yllcorner, cellsize, nodata_value)
+        let dx = dx.or(cellsize);
+        let dy = dy.or(cellsize);
+        let xllcorner = xllcorner.or_else(|| Some(xllcenter? - dx? / 2.0));
+        let yllcorner = yllcorner.or_else(|| Some(yllcenter? - dy? / 2.0));
+        if let (Some(ncols), Some(nrows), Some(xllcorner), Some(yllcorner), Some(dx), Some(dy)) =
+            (ncols, nrows, xllcorner, yllcorner, dx, dy)
         {
-            Ok(Self {
+            let header = Self {
                 ncols,
                 nrows,
                 xllcorner,
                 yllcorner,
-                cellsize,
-                nodata_value,
-            })
+                dx,
+                dy,
+                nodata_value: Some(nodata_value.unwrap_or(DEFAULT_NODATA_VALUE)),
+            };
+            Ok((header, lines))
         } else {
             Err(GpwError::Parse("incomplete header", None))
         }
     }
+
+    /// Writes the header in the same layout as GPW files, with
+    /// `dx`/`dy` only for non-square pixels.
+    pub fn write<W: Write>(&self, wtr: &mut W) -> Result<(), GpwError> {
+        writeln!(wtr, "ncols         {}", self.ncols)?;
+        writeln!(wtr, "nrows         {}", self.nrows)?;
+        writeln!(wtr, "xllcorner     {}", self.xllcorner)?;
+        writeln!(wtr, "yllcorner     {}", self.yllcorner)?;
+        if self.dx == self.dy {
+            writeln!(wtr, "cellsize      {}", self.dx)?;
+        } else {
+            writeln!(wtr, "dx            {}", self.dx)?;
+            writeln!(wtr, "dy            {}", self.dy)?;
+        }
+        if let Some(nodata_value) = self.nodata_value {
+            writeln!(wtr, "NODATA_value  {}", nodata_value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if `val` is the NODATA sentinel.
+    ///
+    /// The comparison is relative so that textual variants such as
+    /// `-9999.000` or a float32 round trip of the sentinel still
+    /// match.
+    pub fn is_nodata(&self, val: f32) -> bool {
+        self.nodata_value
+            .is_some_and(|nodata| nodata_matches(nodata, f64::from(val)))
+    }
+
+    /// Returns `true` if both headers have the same NODATA sentinel,
+    /// compared as in [`GpwAsciiHeader::is_nodata`].
+    pub fn same_nodata(&self, other: &GpwAsciiHeader) -> bool {
+        match (self.nodata_value, other.nodata_value) {
+            (Some(nodata), Some(other)) => nodata_matches(nodata, other),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Relative tolerance used when comparing samples to NODATA.
+const NODATA_TOLERANCE: f64 = 1e-6;
+
+fn nodata_matches(nodata: f64, val: f64) -> bool {
+    // A NaN sentinel, such as GDAL's `nan`, never compares equal.
+    if nodata.is_nan() {
+        return val.is_nan();
+    }
+    (val - nodata).abs() <= nodata.abs().max(1.0) * NODATA_TOLERANCE
+}
+
+/// Returns `true` if the next line in `rdr` begins with a header key
+/// rather than a number.
+fn starts_with_key<B: BufRead>(rdr: &mut B) -> Result<bool, GpwError> {
+    let buf = rdr.fill_buf()?;
+    Ok(buf
+        .iter()
+        .find(|b| **b != b' ' && **b != b'\t')
+        .is_some_and(|b| b.is_ascii_alphabetic()))
+}
+
+fn parse_value<T>(key: &'static str, token: Option<&str>) -> Result<T, GpwError>
+where
+    T: FromStr,
+    T::Err: Debug + Send + Sync + 'static,
+{
+    Ok(token
+        .ok_or(GpwError::Parse(key, None))?
+        .parse::<T>()
+        .map_err(|e| (key, e))?)
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -124,32 +189,12 @@ pub struct GpwAscii {
 }
 
 impl GpwAscii {
-    pub fn parse<R: std::io::Read>(rdr: &mut BufReader<R>) -> Result<Self, GpwError> {
-        let header = GpwAsciiHeader::parse(rdr)?;
-        let mut data = Vec::with_capacity(header.nrows);
-        let mut data_line = String::new();
-        let mut row_idx = 0;
-        while 0 != rdr.read_line(&mut data_line)? {
-            let mut row = Vec::with_capacity(header.ncols);
-            for (col_idx, cell) in data_line.split_whitespace().enumerate() {
-                let sample = if cell == header.nodata_value {
-                    None
-                } else {
-                    Some(cell.parse::<f32>().map_err(|e| {
-                        (
-                            "cell parse error",
-                            format!("row {}, col {}, err {}", row_idx, col_idx, e),
-                        )
-                    })?)
-                };
-                row.push(sample);
-            }
-            assert_eq!(row.len(), header.ncols);
-            row_idx += 1;
-            data.push(row);
-            data_line.clear();
-        }
-        assert_eq!(data.len(), header.nrows);
+    pub fn parse<B: BufRead>(rdr: &mut B) -> Result<Self, GpwError> {
+        let rows = GpwAsciiRows::new(rdr)?;
+        let header = rows.header.clone();
+        let data = rows
+            .map(|row| row.map(|(_row_idx, row)| row))
+            .collect::<Result<Vec<_>, _>>()?;
         Ok(Self {
             header,
             data,
@@ -157,3 +202,181 @@ impl GpwAscii {
         })
     }
 }
+
+/// Streaming reader which parses a GPW ASCII file one row at a time.
+pub struct GpwAsciiRows<B> {
+    pub header: GpwAsciiHeader,
+    pub filename: Option<String>,
+    /// Number of negative or non-finite samples dropped or clamped so
+    /// far.
+    pub suspicious: usize,
+    negative_policy: NegativePolicy,
+    /// Columns whose samples are parsed.
+    columns: Range<usize>,
+    rdr: B,
+    data_line: String,
+    /// One-based number of the last line read.
+    line: usize,
+    row_idx: usize,
+    done: bool,
+}
+
+impl<B: BufRead> GpwAsciiRows<B> {
+    /// Parses the header from `rdr` and positions the reader at the
+    /// first data row.
+    pub fn new(mut rdr: B) -> Result<Self, GpwError> {
+        let (header, header_lines) = GpwAsciiHeader::parse_counted(&mut rdr)?;
+        Ok(Self {
+            columns: 0..header.ncols,
+            header,
+            filename: None,
+            suspicious: 0,
+            negative_policy: NegativePolicy::Error,
+            rdr,
+            data_line: String::new(),
+            line: header_lines,
+            row_idx: 0,
+            done: false,
+        })
+    }
+
+    /// Sets the file name reported in errors.
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// Sets how negative samples are handled.
+    pub fn with_negative_policy(mut self, policy: NegativePolicy) -> Self {
+        self.negative_policy = policy;
+        self
+    }
+
+    fn location(&self, column: usize) -> Location {
+        Location {
+            file: self.filename.clone(),
+            line: self.line,
+            column,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<bool, GpwError> {
+        self.data_line.clear();
+        if 0 == self.rdr.read_line(&mut self.data_line)? {
+            return Ok(false);
+        }
+        self.line += 1;
+        Ok(true)
+    }
+
+    fn parse_row(&mut self) -> Result<Option<Row>, GpwError> {
+        if self.row_idx == self.header.nrows {
+            // Only whitespace may follow the last row.
+            while self.read_line()? {
+                if !self.data_line.trim().is_empty() {
+                    return Err(GpwError::TrailingData {
+                        location: self.location(1),
+                    });
+                }
+            }
+            return Ok(None);
+        }
+        if !self.read_line()? {
+            return Err(GpwError::MissingRows {
+                file: self.filename.clone(),
+                expected: self.header.nrows,
+                found: self.row_idx,
+            });
+        }
+        let row_idx = self.row_idx;
+        let mut row = Vec::with_capacity(self.header.ncols);
+        for (col_idx, cell) in self.data_line.split_whitespace().enumerate() {
+            if col_idx == self.header.ncols {
+                return Err(GpwError::LongRow {
+                    location: self.location(col_idx + 1),
+                    expected: self.header.ncols,
+                    found: self.data_line.split_whitespace().count(),
+                });
+            }
+            if !self.columns.contains(&col_idx) {
+                row.push(None);
+                continue;
+            }
+            let val = match cell.parse::<f32>() {
+                Ok(val) => val,
+                Err(_) => {
+                    return Err(GpwError::InvalidCell {
+                        location: self.location(col_idx + 1),
+                        value: cell.to_string(),
+                    })
+                }
+            };
+            let sample = classify_sample(
+                &self.header,
+                self.negative_policy,
+                &mut self.suspicious,
+                val,
+            )
+            .map_err(|()| GpwError::NegativeCell {
+                location: self.location(col_idx + 1),
+                value: val,
+            })?;
+            row.push(sample);
+        }
+        if row.len() < self.header.ncols {
+            return Err(GpwError::ShortRow {
+                location: self.location(row.len() + 1),
+                expected: self.header.ncols,
+                found: row.len(),
+            });
+        }
+        self.row_idx += 1;
+        Ok(Some((row_idx, row)))
+    }
+}
+
+impl<B: BufRead> Iterator for GpwAsciiRows<B> {
+    type Item = Result<Row, GpwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let row = self.parse_row();
+        self.done = !matches!(row, Ok(Some(_)));
+        row.transpose()
+    }
+}
+
+impl<B: BufRead> Raster for GpwAsciiRows<B> {
+    fn header(&self) -> &GpwAsciiHeader {
+        &self.header
+    }
+
+    fn suspicious(&self) -> usize {
+        self.suspicious
+    }
+
+    fn skip_rows(&mut self, n: usize) -> Result<(), GpwError> {
+        for _ in 0..n.min(self.header.nrows - self.row_idx) {
+            if !self.read_line()? {
+                return Err(GpwError::MissingRows {
+                    file: self.filename.clone(),
+                    expected: self.header.nrows,
+                    found: self.row_idx,
+                });
+            }
+            self.row_idx += 1;
+        }
+        Ok(())
+    }
+
+    fn restrict_columns(&mut self, cols: Range<usize>) {
+        self.columns = cols;
+    }
+
+    fn read_remainder(&mut self) -> Result<(), GpwError> {
+        io::copy(&mut self.rdr, &mut io::sink())?;
+        Ok(())
+    }
+}
@@ -1,4 +1,17 @@
+pub mod accumulate;
+pub mod aggregate;
 pub mod args;
+pub mod clip;
 pub mod error;
 pub mod generate;
+pub mod geotiff;
 pub mod gpwascii;
+pub mod mosaic;
+pub mod raster;
+pub mod rasterize;
+pub mod source;
+#[cfg(test)]
+mod test_util;
+
+/// Name and version recorded in the header of files we write.
+pub const TOOL: &str = concat!("gpwgen ", env!("CARGO_PKG_VERSION"));
@@ -1,20 +1,33 @@
 use anyhow::{anyhow, Result};
-use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
 use clap::Parser;
+use gpwformat::h3tess::{Aggregation, H3TessHeader, H3TessReader, H3TessWriter, SourceInfo};
 use gpwgen::{
-    args::{Args, Combine, Tessellate},
-    generate::gen_to_disk,
-    gpwascii::GpwAscii,
+    accumulate::CellAccumulator,
+    aggregate::{AggregateCompactor, Rollup},
+    args::{Args, Combine, Rasterize, Tessellate},
+    clip::{Clip, Clipped},
+    generate::{cells_coarser_than_pixels, gen_to_disk, Totals, Weighting},
+    gpwascii::GpwAsciiHeader,
+    mosaic::Mosaic,
+    raster::{self, NegativePolicy, Raster},
+    source::{Checksum, Source},
+    TOOL,
 };
 use hextree::{
-    compaction::Compactor,
     h3ron::{FromH3Index, H3Cell},
     HexTreeMap,
 };
+use rayon::prelude::*;
 use std::{
     fs::File,
-    io::{BufReader, BufWriter, ErrorKind},
-    path::PathBuf,
+    io::{BufReader, BufWriter, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Instant,
 };
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
@@ -23,113 +36,472 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// Memory for cells shared by every rollup of `combine` when no
+/// `--memory-limit` is given.
+const DEFAULT_MEMORY_LIMIT: u64 = 4 << 30;
 
 fn main() -> Result<()> {
     let args = Args::parse();
     match args {
         Args::Tessellate(tess_args) => tessellate(tess_args)?,
         Args::Combine(combine_args) => combine(combine_args)?,
+        Args::Rasterize(rasterize_args) => rasterize(rasterize_args)?,
     };
     Ok(())
 }
 
-fn tessellate(
-    Tessellate {
-        resolution,
-        sources,
-        outdir,
-    }: Tessellate,
-) -> Result<()> {
-    // Open all source and destination files at the same time,
-    // otherwise fail fast.
-    let files = sources
-        .iter()
-        .map(|src_path| -> Result<(File, File)> {
-            let src_file = File::open(src_path)?;
-
-            // Create the path to the output file with H3 resolution added and
-            // gpwh3 extension.
-            let dst_path = {
-                let src_filename = src_path
-                    .file_name()
-                    .ok_or_else(|| anyhow!(format!("Not a file {:?}", src_path)))?;
-                let mut dst = PathBuf::new();
-                dst.push(&outdir);
-                dst.push(src_filename);
-                dst.set_extension(format!("res{}.h3tess", resolution));
-                dst
-            };
-            let dst_file = File::create(dst_path)?;
-            Ok((src_file, dst_file))
-        })
-        .collect::<Result<Vec<(File, File)>>>()?;
+fn tessellate(args: Tessellate) -> Result<()> {
+    init_thread_pool(args.threads)?;
+    let clip = match &args.clip {
+        Some(path) => Some(Clip::from_geojson(path)?),
+        None => None,
+    };
+    let clip = match (clip, args.bbox) {
+        (Some(clip), Some(bbox)) => Some(clip.with_bbox(bbox)?),
+        (None, Some(bbox)) => Some(Clip::from_bbox(bbox)),
+        (clip, None) => clip,
+    };
 
-    for (src_file, dst_file) in files {
-        let mut rdr = BufReader::new(src_file);
-        let mut dst = BufWriter::new(dst_file);
-        let data = GpwAscii::parse(&mut rdr).unwrap();
-        gen_to_disk(data, &mut dst)
+    // List every source up front. Each job is a label, the sources
+    // read and the output file name.
+    let mut jobs = Vec::new();
+    let failed = AtomicUsize::new(0);
+    for src_path in &args.sources {
+        match Source::expand(src_path) {
+            Ok(sources) => {
+                for source in sources {
+                    let file_name = source.file_name();
+                    jobs.push((source.to_string(), vec![source], file_name));
+                }
+            }
+            Err(e) if args.keep_going => {
+                eprintln!("error: {}: {}", src_path.display(), e);
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => return Err(anyhow!("{}: {}", src_path.display(), e)),
+        }
     }
+    if let Some(name) = &args.mosaic {
+        let sources = jobs
+            .into_iter()
+            .flat_map(|(_, sources, _)| sources)
+            .collect();
+        jobs = vec![(name.clone(), sources, name.clone())];
+    }
+
+    // Each job parses and tessellates one source at a time, so with
+    // more than one job parsing the next source overlaps tessellating
+    // the current one while at most `jobs` sources are in memory.
+    let total = jobs.len() + failed.load(Ordering::Relaxed);
+    let queue = Mutex::new(jobs.into_iter());
+    let first_error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+    thread::scope(|s| {
+        for _ in 0..args.jobs.max(1) {
+            s.spawn(|| loop {
+                if !args.keep_going && first_error.lock().expect("poisoned").is_some() {
+                    break;
+                }
+                let Some((label, sources, file_name)) = queue.lock().expect("poisoned").next()
+                else {
+                    break;
+                };
+                // Create the path to the output file with H3 resolution
+                // added and h3tess extension.
+                let dst_path = {
+                    let mut dst = PathBuf::new();
+                    dst.push(&args.outdir);
+                    dst.push(file_name);
+                    dst.set_extension(format!("res{}.h3tess", args.resolution));
+                    dst
+                };
+                let start = Instant::now();
+                let result = open_job(&args, &label, &sources).and_then(|opened| {
+                    let dst_file = File::create(&dst_path)
+                        .map_err(|e| anyhow!("{}: {}", dst_path.display(), e))?;
+                    let cells = CellAccumulator::new(&dst_path);
+                    tessellate_source(
+                        &args,
+                        &label,
+                        &sources,
+                        opened,
+                        clip.as_ref(),
+                        cells,
+                        dst_file,
+                    )
+                    .inspect_err(|_| {
+                        // Don't leave a partial output file behind.
+                        if let Err(remove_err) = std::fs::remove_file(&dst_path) {
+                            eprintln!("warning: {}: {}", dst_path.display(), remove_err);
+                        }
+                    })
+                });
+                match result {
+                    Ok(()) => println!(
+                        "{}: tessellated in {:.1}s",
+                        label,
+                        start.elapsed().as_secs_f64()
+                    ),
+                    Err(e) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        if args.keep_going {
+                            eprintln!("error: {}", e);
+                        } else {
+                            first_error.lock().expect("poisoned").get_or_insert(e);
+                        }
+                    }
+                }
+            });
+        }
+    });
 
+    if let Some(e) = first_error.into_inner().expect("poisoned") {
+        return Err(e);
+    }
+    let failed = failed.into_inner();
+    if failed > 0 {
+        return Err(anyhow!("{} of {} sources failed", failed, total));
+    }
     Ok(())
 }
 
+/// Opens the raster of one tessellate job: its only source, or a
+/// mosaic of all of them with `--mosaic`, along with the checksum of
+/// each source.
+fn open_job(
+    args: &Tessellate,
+    label: &str,
+    sources: &[Source],
+) -> Result<(Box<dyn Raster + Send>, Vec<Checksum>)> {
+    if args.mosaic.is_none() {
+        if let [source] = sources {
+            let (raster, checksum) = raster::open(source, args.negative)?;
+            return Ok((raster, vec![checksum]));
+        }
+    }
+    let mut members = Vec::new();
+    let mut checksums = Vec::new();
+    for source in sources {
+        let (raster, checksum) = raster::open(source, args.negative)?;
+        members.push((source.to_string(), raster));
+        checksums.push(checksum);
+    }
+    let mosaic = Mosaic::new(members)?;
+    let gap_pixels = mosaic.layout().gap_pixels;
+    if gap_pixels > 0 {
+        eprintln!(
+            "warning: {}: {} pixels aren't covered by any source and are treated as NODATA",
+            label, gap_pixels
+        );
+    }
+    Ok((Box::new(mosaic), checksums))
+}
+
+fn tessellate_source(
+    args: &Tessellate,
+    label: &str,
+    sources: &[Source],
+    (mut raster, checksums): (Box<dyn Raster + Send>, Vec<Checksum>),
+    clip: Option<&Clip>,
+    cells: CellAccumulator,
+    dst_file: File,
+) -> Result<()> {
+    // Checksums are only known once the sources have been read, and
+    // are filled in when the output is finished.
+    let sources = sources
+        .iter()
+        .map(|source| SourceInfo {
+            name: source.file_name(),
+            checksum: 0,
+        })
+        .collect();
+    let mut dst = H3TessWriter::new(
+        BufWriter::new(dst_file),
+        H3TessHeader::new(TOOL, args.resolution, sources),
+    )?;
+    if let Some(clip) = clip {
+        let window = clip.window(raster.header());
+        if window.is_empty() {
+            eprintln!("warning: {}: no pixels inside the clip region", label);
+        }
+        raster = Box::new(Clipped::new(raster, window));
+    }
+    let header = raster.header();
+    if args.weighting == Weighting::Even && cells_coarser_than_pixels(header, args.resolution) {
+        eprintln!(
+            "warning: res {} H3 cells are larger than {}° pixels, \
+             pixels without a cell centroid are assigned to a single cell, \
+             consider --weighting area",
+            args.resolution, header.dx
+        );
+    }
+    let totals = gen_to_disk(
+        &mut *raster,
+        args.resolution,
+        args.weighting,
+        clip,
+        cells,
+        &mut dst,
+    )?;
+    raster.read_remainder()?;
+    for (idx, checksum) in checksums.iter().enumerate() {
+        dst.set_checksum(idx, checksum.value());
+    }
+    dst.finish()?;
+    println!(
+        "{}: {} suspicious negative or non-finite cells",
+        label,
+        raster.suspicious()
+    );
+    audit(label, "raster", totals, args.tolerance)
+}
+
 fn combine(
     Combine {
         resolution,
+        aggregate,
         sources,
         output,
+        tolerance,
+        legacy,
+        lossless,
+        memory_limit,
+        threads,
     }: Combine,
 ) -> Result<()> {
-    // Open all source files at the same time, otherwise fail fast.
-    let sources = sources
+    init_thread_pool(threads)?;
+    if lossless && !resolution.is_single() {
+        return Err(anyhow!("--lossless requires a single resolution"));
+    }
+    if lossless && memory_limit.is_some() {
+        return Err(anyhow!("--lossless does not support --memory-limit"));
+    }
+
+    // Open all source files and validate their headers at the same
+    // time, otherwise fail fast.
+    let readers = sources
         .iter()
-        .map(File::open)
-        .collect::<std::io::Result<Vec<File>>>()?;
-    let output_file = File::create(output)?;
+        .map(|path| -> Result<_> {
+            let rdr = BufReader::new(File::open(path)?);
+            if legacy {
+                Ok(H3TessReader::legacy(rdr))
+            } else {
+                H3TessReader::new(rdr).map_err(|e| anyhow!("{}: {}", path.display(), e))
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    let mut map: HexTreeMap<f32, _> = HexTreeMap::with_compactor(SummationCompactor { resolution });
+    let mut source_infos: Vec<SourceInfo> = Vec::new();
+    for (path, rdr) in sources.iter().zip(&readers) {
+        let infos = match rdr.header() {
+            Some(header) => header.sources.clone(),
+            None => vec![SourceInfo {
+                name: path.display().to_string(),
+                checksum: 0,
+            }],
+        };
+        for info in infos {
+            if !source_infos.contains(&info) {
+                source_infos.push(info);
+            }
+        }
+    }
 
-    for source in sources {
-        let mut rdr = BufReader::new(source);
-        loop {
-            match (rdr.read_u64::<LE>(), rdr.read_f32::<LE>()) {
-                (Ok(h3_index), Ok(val)) => {
-                    let cell = H3Cell::from_h3index(h3_index);
-                    map.insert(cell, val)
-                }
-                (Err(e), _) if e.kind() == ErrorKind::UnexpectedEof => break,
-                (err @ Err(_), _) => {
-                    err?;
-                }
-                (_, err @ Err(_)) => {
-                    err?;
+    // Sources are rolled up a batch at a time, so at most one rollup
+    // per thread plus the final rollup are alive for each resolution,
+    // and they share the memory limit.
+    let batch_size = rayon::current_num_threads().clamp(1, sources.len().max(1));
+    let new_rollup = |res: u8, spill_prefix: &Path| {
+        let resolutions = u64::from(resolution.max - resolution.min) + 1;
+        let rollups = resolutions * (batch_size as u64 + 1);
+        let limit = memory_limit.unwrap_or(DEFAULT_MEMORY_LIMIT);
+        let cells = CellAccumulator::new(spill_prefix).with_memory_limit(limit / rollups);
+        Rollup::new(res, aggregate, cells)
+    };
+    // Create every output up front, with one rollup per resolution.
+    let mut outputs = Vec::new();
+    for res in resolution.iter() {
+        let path = resolution.output_path(&output, res);
+        let wtr = H3TessWriter::new(
+            BufWriter::new(File::create(&path)?),
+            H3TessHeader::new(TOOL, res, source_infos.clone()).with_aggregation(aggregate),
+        )?;
+        let rollup = new_rollup(res, &path);
+        outputs.push((res, wtr, rollup));
+    }
+
+    if lossless {
+        let records = sources.iter().zip(readers).flat_map(|(path, rdr)| {
+            rdr.map(move |record| record.map_err(|e| anyhow!("{}: {}", path.display(), e)))
+        });
+        let (_, wtr, _) = outputs.pop().expect("one resolution");
+        return combine_lossless(records, resolution.min, aggregate, wtr, tolerance);
+    }
+
+    // Roll up each batch of sources in parallel, then merge them in
+    // source order so the output doesn't depend on scheduling.
+    let mut jobs = sources.iter().zip(readers).enumerate();
+    loop {
+        let batch: Vec<_> = jobs.by_ref().take(batch_size).collect();
+        if batch.is_empty() {
+            break;
+        }
+        let partials = batch
+            .into_par_iter()
+            .map(|(idx, (path, rdr))| -> Result<Vec<Rollup>> {
+                let mut rollups: Vec<Rollup> = resolution
+                    .iter()
+                    .map(|res| {
+                        let path = resolution.output_path(&output, res);
+                        new_rollup(res, Path::new(&format!("{}.{}", path.display(), idx)))
+                    })
+                    .collect();
+                for record in rdr {
+                    let (h3_index, val) =
+                        record.map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+                    for rollup in &mut rollups {
+                        rollup.add(h3_index, val)?;
+                    }
                 }
+                Ok(rollups)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for rollups in partials {
+            for ((_, _, rollup), partial) in outputs.iter_mut().zip(rollups) {
+                rollup.absorb(partial)?;
+            }
+        }
+    }
+
+    for (res, mut wtr, rollup) in outputs {
+        let conserves_total = rollup.conserves_total();
+        let totals = rollup.finish(&mut wtr)?;
+        wtr.finish()?;
+        if conserves_total {
+            let label = if resolution.is_single() {
+                "combine".to_string()
+            } else {
+                format!("combine res{}", res)
             };
+            audit(&label, "h3tess", totals, tolerance)?;
         }
     }
+    Ok(())
+}
 
-    let mut wtr = BufWriter::new(output_file);
+/// Combines `records` with hextree compaction, which only merges
+/// complete sets of siblings.
+fn combine_lossless<W: Write + Seek>(
+    records: impl Iterator<Item = Result<(u64, f32)>>,
+    resolution: u8,
+    aggregate: Aggregation,
+    mut wtr: H3TessWriter<W>,
+    tolerance: f64,
+) -> Result<()> {
+    let compactor = AggregateCompactor::new(resolution, aggregate);
+    let mut map: HexTreeMap<f32, _> = HexTreeMap::with_compactor(compactor);
+    let mut totals = Totals::default();
+    for record in records {
+        let (h3_index, val) = record?;
+        let val = compactor.leaf_value(val);
+        totals.source += f64::from(val);
+        map.insert(H3Cell::from_h3index(h3_index), val)
+    }
     for (cell, val) in map.iter() {
-        wtr.write_u64::<LE>(**cell)?;
-        wtr.write_f32::<LE>(*val)?;
+        wtr.write(**cell, *val)?;
+        totals.written += f64::from(*val);
     }
+    wtr.finish()?;
 
-    Ok(())
+    if !compactor.conserves_total() {
+        return Ok(());
+    }
+    audit("combine", "h3tess", totals, tolerance)
 }
 
-struct SummationCompactor {
-    resolution: u8,
+/// Sizes the global rayon pool, which otherwise uses one thread per
+/// CPU.
+fn init_thread_pool(threads: Option<usize>) -> Result<()> {
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+    Ok(())
 }
 
-impl Compactor<f32> for SummationCompactor {
-    fn compact(&mut self, res: u8, children: [Option<&f32>; 7]) -> Option<f32> {
-        if res < self.resolution {
-            return None;
+/// Writes the values of an h3tess file to an ESRI ASCII grid.
+fn rasterize(args: Rasterize) -> Result<()> {
+    init_thread_pool(args.threads)?;
+    let mut header = match (
+        &args.like,
+        args.ncols,
+        args.nrows,
+        args.xllcorner,
+        args.yllcorner,
+        args.cellsize,
+    ) {
+        (Some(path), ..) => {
+            let sources = Source::expand(path)?;
+            let [source] = &sources[..] else {
+                return Err(anyhow!("{}: expected a single raster", path.display()));
+            };
+            raster::open(source, NegativePolicy::Error)?
+                .0
+                .header()
+                .clone()
         }
-        if let [Some(v0), Some(v1), Some(v2), Some(v3), Some(v4), Some(v5), Some(v6)] = children {
-            return Some(v0 + v1 + v2 + v3 + v4 + v5 + v6);
-        };
-        None
+        (None, Some(ncols), Some(nrows), Some(xllcorner), Some(yllcorner), Some(cellsize)) => {
+            GpwAsciiHeader {
+                ncols,
+                nrows,
+                xllcorner,
+                yllcorner,
+                dx: cellsize,
+                dy: cellsize,
+                nodata_value: None,
+            }
+        }
+        _ => return Err(anyhow!("either --like or a complete grid is required")),
+    };
+    if header.ncols == 0 || header.nrows == 0 || header.dx <= 0.0 || header.dy <= 0.0 {
+        return Err(anyhow!("empty grid"));
     }
+    header.nodata_value = Some(args.nodata_value);
+
+    let rdr = BufReader::new(File::open(&args.source)?);
+    let records = if args.legacy {
+        H3TessReader::legacy(rdr)
+    } else {
+        H3TessReader::new(rdr).map_err(|e| anyhow!("{}: {}", args.source.display(), e))?
+    };
+    let mut dst = BufWriter::new(File::create(&args.output)?);
+    let totals = gpwgen::rasterize::rasterize(records, &header, &mut dst)?;
+    audit(
+        &args.source.display().to_string(),
+        "h3tess",
+        totals,
+        args.tolerance,
+    )
+}
+
+/// Reports population conservation for one run and fails if the
+/// written total drifted more than `tolerance` from the source.
+fn audit(label: &str, source_kind: &str, totals: Totals, tolerance: f64) -> Result<()> {
+    let error = totals.relative_error();
+    if totals.clipped != 0.0 {
+        println!("{}: clipped total {:.3}", label, totals.clipped);
+    }
+    println!(
+        "{}: {} total {:.3}, written total {:.3}, relative error {:e}",
+        label, source_kind, totals.source, totals.written, error
+    );
+    if totals.exceeds(tolerance) {
+        return Err(anyhow!(
+            "{}: relative error {:e} exceeds tolerance {:e}",
+            label,
+            error,
+            tolerance
+        ));
+    }
+    Ok(())
 }
@@ -0,0 +1,425 @@
+use crate::{
+    error::GpwError,
+    gpwascii::GpwAsciiHeader,
+    raster::{Raster, Row},
+};
+use std::ops::Range;
+
+/// Largest misalignment between a member's grid and the mosaic's, as
+/// a fraction of a pixel.
+const ALIGNMENT_TOLERANCE: f64 = 1e-3;
+
+/// Where member grids sit in the union of their extents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MosaicLayout {
+    /// Geometry of the union extent.
+    pub header: GpwAsciiHeader,
+    /// Row and column of each member's top-left pixel in the union
+    /// extent, in member order.
+    pub offsets: Vec<(usize, usize)>,
+    /// Number of pixels in the union extent not covered by any member.
+    pub gap_pixels: usize,
+}
+
+impl MosaicLayout {
+    /// Lays out named member grids, which must share a cell size and
+    /// NODATA value, be aligned to the same grid and not overlap.
+    pub fn new(members: &[(&str, &GpwAsciiHeader)]) -> Result<Self, GpwError> {
+        let first = match members.first() {
+            Some((_, header)) => *header,
+            None => return Err(GpwError::InvalidMosaic("no member rasters".to_string())),
+        };
+        let (dx, dy) = (first.dx, first.dy);
+        let (mut left, mut right) = (f64::INFINITY, f64::NEG_INFINITY);
+        let (mut bottom, mut top) = (f64::INFINITY, f64::NEG_INFINITY);
+        for (name, header) in members {
+            if !same_size(header.dx, dx, header.ncols) || !same_size(header.dy, dy, header.nrows) {
+                return Err(GpwError::InvalidMosaic(format!(
+                    "{}: cell size {}x{} differs from {}x{}",
+                    name, header.dx, header.dy, dx, dy
+                )));
+            }
+            if !header.same_nodata(first) {
+                return Err(GpwError::InvalidMosaic(format!(
+                    "{}: NODATA value {:?} differs from {:?}",
+                    name, header.nodata_value, first.nodata_value
+                )));
+            }
+            left = left.min(header.xllcorner);
+            right = right.max(header.xllcorner + header.ncols as f64 * dx);
+            bottom = bottom.min(header.yllcorner);
+            top = top.max(header.yllcorner + header.nrows as f64 * dy);
+        }
+        let ncols = ((right - left) / dx).round() as usize;
+        let nrows = ((top - bottom) / dy).round() as usize;
+        let header = GpwAsciiHeader {
+            ncols,
+            nrows,
+            xllcorner: left,
+            yllcorner: top - nrows as f64 * dy,
+            dx,
+            dy,
+            nodata_value: first.nodata_value,
+        };
+
+        let offsets = members
+            .iter()
+            .map(|(name, member)| {
+                let member_top = member.yllcorner + member.nrows as f64 * dy;
+                let row_offset = grid_offset(name, (top - member_top) / dy)?;
+                let col_offset = grid_offset(name, (member.xllcorner - left) / dx)?;
+                Ok((row_offset, col_offset))
+            })
+            .collect::<Result<Vec<_>, GpwError>>()?;
+
+        // Members can't overlap, so whatever they don't cover is a gap.
+        let mut covered = 0;
+        for (a, (name, member)) in members.iter().enumerate() {
+            let (row, col) = offsets[a];
+            for (b, (other_name, other)) in members.iter().enumerate().skip(a + 1) {
+                let (other_row, other_col) = offsets[b];
+                if row < other_row + other.nrows
+                    && other_row < row + member.nrows
+                    && col < other_col + other.ncols
+                    && other_col < col + member.ncols
+                {
+                    return Err(GpwError::InvalidMosaic(format!(
+                        "{} overlaps {}",
+                        name, other_name
+                    )));
+                }
+            }
+            covered += member.nrows * member.ncols;
+        }
+
+        Ok(Self {
+            header,
+            offsets,
+            gap_pixels: nrows * ncols - covered,
+        })
+    }
+}
+
+/// Several rasters with the same pixel size read as a single raster,
+/// such as the eight tiles of a global GPW dataset or adjoining
+/// regional grids.
+///
+/// Pixel positions are derived from the mosaic's origin rather than
+/// each member's, so pixels either side of a seam share exactly the
+/// same edge. Pixels in gaps between members are NODATA.
+pub struct Mosaic {
+    layout: MosaicLayout,
+    members: Vec<Member>,
+    next_row: usize,
+    done: bool,
+}
+
+struct Member {
+    name: String,
+    raster: Box<dyn Raster + Send>,
+    /// Position of the member's top-left pixel in the mosaic.
+    row_offset: usize,
+    col_offset: usize,
+    /// None of the member's columns are parsed, so its rows are
+    /// skipped.
+    clipped: bool,
+}
+
+impl Mosaic {
+    /// Builds a mosaic from named member rasters, laid out as
+    /// described by [`MosaicLayout::new`].
+    pub fn new(members: Vec<(String, Box<dyn Raster + Send>)>) -> Result<Self, GpwError> {
+        let headers: Vec<(&str, &GpwAsciiHeader)> = members
+            .iter()
+            .map(|(name, raster)| (name.as_str(), raster.header()))
+            .collect();
+        let layout = MosaicLayout::new(&headers)?;
+        let members = members
+            .into_iter()
+            .zip(&layout.offsets)
+            .map(|((name, raster), &(row_offset, col_offset))| Member {
+                name,
+                raster,
+                row_offset,
+                col_offset,
+                clipped: false,
+            })
+            .collect();
+        Ok(Self {
+            layout,
+            members,
+            next_row: 0,
+            done: false,
+        })
+    }
+
+    pub fn layout(&self) -> &MosaicLayout {
+        &self.layout
+    }
+
+    fn parse_row(&mut self) -> Result<Option<Row>, GpwError> {
+        let row_idx = self.next_row;
+        if row_idx >= self.layout.header.nrows {
+            return Ok(None);
+        }
+        let mut row = vec![None; self.layout.header.ncols];
+        for member in &mut self.members {
+            let nrows = member.raster.header().nrows;
+            if row_idx < member.row_offset || row_idx >= member.row_offset + nrows {
+                continue;
+            }
+            if member.clipped {
+                member.raster.skip_rows(1)?;
+                continue;
+            }
+            let (_, samples) = member.raster.next().ok_or_else(|| GpwError::MissingRows {
+                file: Some(member.name.clone()),
+                expected: nrows,
+                found: row_idx - member.row_offset,
+            })??;
+            row[member.col_offset..member.col_offset + samples.len()].copy_from_slice(&samples);
+        }
+        self.next_row += 1;
+        Ok(Some((row_idx, row)))
+    }
+}
+
+impl Iterator for Mosaic {
+    type Item = Result<Row, GpwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let row = self.parse_row();
+        self.done = !matches!(row, Ok(Some(_)));
+        row.transpose()
+    }
+}
+
+impl Raster for Mosaic {
+    fn header(&self) -> &GpwAsciiHeader {
+        &self.layout.header
+    }
+
+    fn suspicious(&self) -> usize {
+        self.members
+            .iter()
+            .map(|member| member.raster.suspicious())
+            .sum()
+    }
+
+    fn skip_rows(&mut self, n: usize) -> Result<(), GpwError> {
+        let end = (self.next_row + n).min(self.layout.header.nrows);
+        for member in &mut self.members {
+            let first = self.next_row.max(member.row_offset);
+            let last = end.min(member.row_offset + member.raster.header().nrows);
+            if first < last {
+                member.raster.skip_rows(last - first)?;
+            }
+        }
+        self.next_row = end;
+        Ok(())
+    }
+
+    fn restrict_columns(&mut self, cols: Range<usize>) {
+        for member in &mut self.members {
+            let ncols = member.raster.header().ncols;
+            let local = |col: usize| {
+                col.clamp(member.col_offset, member.col_offset + ncols) - member.col_offset
+            };
+            let local_cols = local(cols.start)..local(cols.end);
+            member.clipped = local_cols.is_empty();
+            member.raster.restrict_columns(local_cols);
+        }
+    }
+
+    fn read_remainder(&mut self) -> Result<(), GpwError> {
+        for member in &mut self.members {
+            member.raster.read_remainder()?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns `true` if `len` pixels of size `a` drift from the same
+/// number of size `b` by no more than the alignment tolerance.
+fn same_size(a: f64, b: f64, len: usize) -> bool {
+    (a - b).abs() * len as f64 <= b.abs() * ALIGNMENT_TOLERANCE
+}
+
+/// Rounds a member's offset in pixels, failing if it isn't on the
+/// mosaic's grid.
+fn grid_offset(name: &str, offset: f64) -> Result<usize, GpwError> {
+    let rounded = offset.round();
+    if (offset - rounded).abs() > ALIGNMENT_TOLERANCE {
+        return Err(GpwError::InvalidMosaic(format!(
+            "{}: not aligned to the mosaic grid (offset {} pixels)",
+            name, offset
+        )));
+    }
+    Ok(rounded as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clip::{Clipped, Window},
+        gpwascii::GpwAsciiRows,
+    };
+    use std::io::Cursor;
+
+    fn member(xll: f64, yll: f64, rows: &str) -> (String, Box<dyn Raster + Send>) {
+        let file = format!(
+            "ncols 2\nnrows 2\nxllcorner {}\nyllcorner {}\ncellsize 0.5\nNODATA_value -9999\n{}",
+            xll, yll, rows
+        );
+        let name = format!("{},{}", xll, yll);
+        let rows = GpwAsciiRows::new(Cursor::new(file.into_bytes())).unwrap();
+        (name, Box::new(rows))
+    }
+
+    #[test]
+    fn test_mosaic() {
+        let mosaic = Mosaic::new(vec![
+            member(-1.0, 0.0, "1 2\n3 4\n"),
+            member(0.0, 0.0, "5 6\n7 8\n"),
+            member(-1.0, -1.0, "9 10\n11 -9999\n"),
+            member(0.0, -1.0, "13 14\n15 16\n"),
+        ])
+        .unwrap();
+        assert_eq!(
+            mosaic.header(),
+            &GpwAsciiHeader {
+                ncols: 4,
+                nrows: 4,
+                xllcorner: -1.0,
+                yllcorner: -1.0,
+                dx: 0.5,
+                dy: 0.5,
+                nodata_value: Some(-9999.0),
+            }
+        );
+        let rows = mosaic.collect::<Result<Vec<Row>, _>>().unwrap();
+        let values: Vec<Vec<Option<f32>>> = rows.into_iter().map(|(_, row)| row).collect();
+        assert_eq!(
+            values,
+            vec![
+                vec![Some(1.0), Some(2.0), Some(5.0), Some(6.0)],
+                vec![Some(3.0), Some(4.0), Some(7.0), Some(8.0)],
+                vec![Some(9.0), Some(10.0), Some(13.0), Some(14.0)],
+                vec![Some(11.0), None, Some(15.0), Some(16.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clipped_mosaic() {
+        let mosaic = Mosaic::new(vec![
+            member(-1.0, 0.0, "1 2\n3 4\n"),
+            member(0.0, 0.0, "5 6\n7 8\n"),
+            member(-1.0, -1.0, "9 10\n11 x\n"),
+            member(0.0, -1.0, "13 14\n15 16\n"),
+        ])
+        .unwrap();
+        // The invalid sample in the lower-left member is never parsed.
+        let window = Window {
+            rows: 1..4,
+            cols: 2..4,
+        };
+        let rows = Clipped::new(Box::new(mosaic), window)
+            .collect::<Result<Vec<Row>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (0, vec![Some(7.0), Some(8.0)]),
+                (1, vec![Some(13.0), Some(14.0)]),
+                (2, vec![Some(15.0), Some(16.0)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mosaic_gaps() {
+        let mosaic = Mosaic::new(vec![
+            member(-1.0, 0.0, "1 2\n3 4\n"),
+            member(0.0, -1.0, "5 6\n7 8\n"),
+        ])
+        .unwrap();
+        assert_eq!(mosaic.layout().offsets, vec![(0, 0), (2, 2)]);
+        assert_eq!(mosaic.layout().gap_pixels, 8);
+        let rows = mosaic.collect::<Result<Vec<Row>, _>>().unwrap();
+        let values: Vec<Vec<Option<f32>>> = rows.into_iter().map(|(_, row)| row).collect();
+        assert_eq!(
+            values,
+            vec![
+                vec![Some(1.0), Some(2.0), None, None],
+                vec![Some(3.0), Some(4.0), None, None],
+                vec![None, None, Some(5.0), Some(6.0)],
+                vec![None, None, Some(7.0), Some(8.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mosaic_layout() {
+        let header = |xll, yll| GpwAsciiHeader {
+            ncols: 2,
+            nrows: 2,
+            xllcorner: xll,
+            yllcorner: yll,
+            dx: 0.5,
+            dy: 0.5,
+            nodata_value: Some(-9999.0),
+        };
+        let a = header(-1.0, 0.0);
+        let b = header(0.0, 0.0);
+        let layout = MosaicLayout::new(&[("a", &a), ("b", &b)]).unwrap();
+        assert_eq!(layout.offsets, vec![(0, 0), (0, 2)]);
+        assert_eq!(layout.gap_pixels, 0);
+
+        let overlapping = header(-0.5, 0.5);
+        assert!(MosaicLayout::new(&[("a", &a), ("b", &overlapping)]).is_err());
+        let nodata = GpwAsciiHeader {
+            nodata_value: None,
+            ..b.clone()
+        };
+        assert!(MosaicLayout::new(&[("a", &a), ("b", &nodata)]).is_err());
+        // Textual variants of the same sentinel match.
+        let variant = GpwAsciiHeader {
+            nodata_value: Some(-3.40282346639e+38),
+            ..b.clone()
+        };
+        let float_min = GpwAsciiHeader {
+            nodata_value: Some(-3.4028234663852886e+38),
+            ..a.clone()
+        };
+        assert!(MosaicLayout::new(&[("a", &float_min), ("b", &variant)]).is_ok());
+        // A cell size within the tolerance of a pixel still drifts too
+        // far across a wide member.
+        let drifting = GpwAsciiHeader {
+            dx: 0.5 * (1.0 + 1e-4),
+            ..b.clone()
+        };
+        assert!(MosaicLayout::new(&[("a", &a), ("b", &drifting)]).is_ok());
+        let wide = GpwAsciiHeader {
+            ncols: 10800,
+            ..drifting
+        };
+        assert!(MosaicLayout::new(&[("a", &a), ("b", &wide)]).is_err());
+        let coarser = GpwAsciiHeader { dx: 1.0, ..b };
+        assert!(MosaicLayout::new(&[("a", &a), ("b", &coarser)]).is_err());
+        assert!(MosaicLayout::new(&[]).is_err());
+    }
+
+    #[test]
+    fn test_misaligned_mosaic() {
+        assert!(Mosaic::new(vec![
+            member(-1.0, 0.0, "1 2\n3 4\n"),
+            member(0.25, 0.0, "5 6\n7 8\n"),
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{
+    error::GpwError,
+    geotiff::GeoTiffRows,
+    gpwascii::{GpwAsciiHeader, GpwAsciiRows},
+    source::{Checksum, Source},
+};
+use std::{fs::File, ops::Range};
+
+/// A single raster row and its zero-based index from the top.
+pub type Row = (usize, Vec<Option<f32>>);
+
+/// A georeferenced grid of population samples read one row at a
+/// time, top row first.
+pub trait Raster: Iterator<Item = Result<Row, GpwError>> {
+    /// Grid geometry and NODATA value.
+    fn header(&self) -> &GpwAsciiHeader;
+
+    /// Number of negative or non-finite samples dropped or clamped so
+    /// far.
+    fn suspicious(&self) -> usize;
+
+    /// Skips the next `n` rows, without parsing their samples where
+    /// the format allows.
+    fn skip_rows(&mut self, n: usize) -> Result<(), GpwError> {
+        for _ in 0..n {
+            match self.next() {
+                Some(row) => {
+                    row?;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Only parses samples in `cols`. Samples outside may be returned
+    /// as NODATA.
+    fn restrict_columns(&mut self, _cols: Range<usize>) {}
+
+    /// Reads whatever is left of the source without parsing it, so its
+    /// [`Checksum`] covers the whole source.
+    fn read_remainder(&mut self) -> Result<(), GpwError> {
+        Ok(())
+    }
+}
+
+/// How negative samples other than NODATA are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NegativePolicy {
+    /// Fail with an error.
+    Error,
+    /// Treat the sample as NODATA.
+    Drop,
+    /// Replace the sample with zero.
+    Clamp,
+}
+
+/// Applies NODATA and `policy` to a raw sample. Non-finite samples
+/// other than NODATA are also treated as NODATA, and counted as
+/// suspicious.
+///
+/// Returns `Err(())` if the sample is negative and `policy` is
+/// [`NegativePolicy::Error`]; the caller knows where the sample came
+/// from and builds the error.
+pub(crate) fn classify_sample(
+    header: &GpwAsciiHeader,
+    policy: NegativePolicy,
+    suspicious: &mut usize,
+    val: f32,
+) -> Result<Option<f32>, ()> {
+    if header.is_nodata(val) {
+        Ok(None)
+    } else if !val.is_finite() {
+        *suspicious += 1;
+        Ok(None)
+    } else if val < 0.0 {
+        match policy {
+            NegativePolicy::Error => Err(()),
+            NegativePolicy::Drop => {
+                *suspicious += 1;
+                Ok(None)
+            }
+            NegativePolicy::Clamp => {
+                *suspicious += 1;
+                Ok(Some(0.0))
+            }
+        }
+    } else {
+        Ok(Some(val))
+    }
+}
+
+/// Opens `source` with the reader matching its file extension:
+/// GeoTIFF for `.tif`/`.tiff`, ESRI ASCII otherwise.
+///
+/// Returns the raster with the CRC-32 of the source's uncompressed
+/// contents, which is complete once the raster has been read and
+/// [`Raster::read_remainder`] called. Errors reading the header name
+/// the source.
+pub fn open(
+    source: &Source,
+    negative_policy: NegativePolicy,
+) -> Result<(Box<dyn Raster + Send>, Checksum), GpwError> {
+    open_unnamed(source, negative_policy).map_err(|error| GpwError::Open {
+        file: source.to_string(),
+        error: Box::new(error),
+    })
+}
+
+fn open_unnamed(
+    source: &Source,
+    negative_policy: NegativePolicy,
+) -> Result<(Box<dyn Raster + Send>, Checksum), GpwError> {
+    let file_name = source.file_name().to_ascii_lowercase();
+    if file_name.ends_with(".tif") || file_name.ends_with(".tiff") {
+        let Source::Plain(path) = source else {
+            return Err(GpwError::Parse(
+                "GeoTIFF must be an uncompressed file",
+                None,
+            ));
+        };
+        let checksum = Checksum::default();
+        let rows = GeoTiffRows::new(File::open(path)?)?
+            .with_filename(source.to_string())
+            .with_negative_policy(negative_policy)
+            .with_checksum(File::open(path)?, checksum.clone());
+        Ok((Box::new(rows), checksum))
+    } else {
+        let (rdr, checksum) = source.open_checksummed()?;
+        let rows = GpwAsciiRows::new(rdr)?
+            .with_filename(source.to_string())
+            .with_negative_policy(negative_policy);
+        Ok((Box::new(rows), checksum))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn test_classify_sample() {
+        let header = GpwAsciiHeader {
+            ncols: 1,
+            nrows: 1,
+            xllcorner: 0.0,
+            yllcorner: 0.0,
+            dx: 1.0,
+            dy: 1.0,
+            nodata_value: Some(-9999.0),
+        };
+        let mut suspicious = 0;
+        let mut classify = |policy, val| classify_sample(&header, policy, &mut suspicious, val);
+        assert_eq!(classify(NegativePolicy::Error, 1.5), Ok(Some(1.5)));
+        assert_eq!(classify(NegativePolicy::Error, -9999.0), Ok(None));
+        assert_eq!(classify(NegativePolicy::Error, -1.0), Err(()));
+        assert_eq!(classify(NegativePolicy::Drop, -1.0), Ok(None));
+        assert_eq!(classify(NegativePolicy::Clamp, -1.0), Ok(Some(0.0)));
+        assert_eq!(classify(NegativePolicy::Error, f32::NAN), Ok(None));
+        assert_eq!(classify(NegativePolicy::Clamp, f32::INFINITY), Ok(None));
+        assert_eq!(suspicious, 4);
+    }
+
+    #[test]
+    fn test_open_names_source() {
+        let dir = TestDir::new("open_names_source");
+        let path = dir.join("bad.asc");
+        std::fs::write(&path, "ncols x\n").unwrap();
+        let source = Source::Plain(path.clone());
+        let Err(e) = open(&source, NegativePolicy::Error) else {
+            panic!("opened a raster with an invalid header");
+        };
+        assert!(e.to_string().starts_with(&format!("{}: ", path.display())));
+
+        let missing = Source::Plain(dir.join("missing.asc"));
+        let Err(e) = open(&missing, NegativePolicy::Error) else {
+            panic!("opened a missing raster");
+        };
+        assert!(e.to_string().contains("missing.asc"));
+    }
+}
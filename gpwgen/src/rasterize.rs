@@ -0,0 +1,211 @@
+use crate::{
+    clip::BoundingBox,
+    error::GpwError,
+    generate::{cell_polygon, pixel_polygon, Totals},
+    gpwascii::GpwAsciiHeader,
+};
+use geo::{Area, BooleanOps, BoundingRect, Translate};
+use gpwformat::FormatError;
+use rayon::prelude::*;
+use std::io::{self, Write};
+
+/// Number of H3 records distributed in parallel at a time.
+const RECORDS_PER_CHUNK: usize = 1 << 16;
+
+/// Distributes the value of every (H3 index, value) record over the
+/// pixels of `header`'s grid the cell overlaps, in proportion to the
+/// area of each overlap, and writes the grid to `dst` as an ESRI ASCII
+/// file.
+///
+/// Pixels no cell overlaps are NODATA. Values for the parts of cells
+/// outside the grid are counted as clipped. Contributions are summed
+/// in record order, so the output is identical regardless of the
+/// number of threads.
+///
+/// The whole grid is held in memory as `f32`, four bytes per pixel;
+/// about 3.7 GB for a global 30 arc-second grid.
+pub fn rasterize<I, W>(
+    mut records: I,
+    header: &GpwAsciiHeader,
+    dst: &mut W,
+) -> Result<Totals, GpwError>
+where
+    I: Iterator<Item = Result<(u64, f32), FormatError>>,
+    W: Write,
+{
+    let len = header.nrows.saturating_mul(header.ncols);
+    let mut grid: Vec<f32> = Vec::new();
+    grid.try_reserve_exact(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("can't allocate a {}x{} grid", header.ncols, header.nrows),
+        )
+    })?;
+    grid.resize(len, f32::NAN);
+    let mut totals = Totals::default();
+    let mut chunk: Vec<(u64, f32)> = Vec::with_capacity(RECORDS_PER_CHUNK);
+    loop {
+        chunk.clear();
+        for record in (&mut records).take(RECORDS_PER_CHUNK) {
+            chunk.push(record?);
+        }
+        if chunk.is_empty() {
+            break;
+        }
+
+        let distributed: Vec<Vec<(usize, f64)>> = chunk
+            .par_iter()
+            .map(|(h3_index, _val)| cell_pixels(header, *h3_index))
+            .collect();
+
+        for ((_h3_index, val), pixels) in chunk.iter().zip(distributed) {
+            let val = f64::from(*val);
+            totals.source += val;
+            let mut inside = 0.0;
+            for (pixel, weight) in pixels {
+                let share = val * weight;
+                let acc = &mut grid[pixel];
+                *acc = if acc.is_nan() {
+                    share as f32
+                } else {
+                    *acc + share as f32
+                };
+                inside += share;
+            }
+            totals.clipped += val - inside;
+        }
+    }
+
+    header.write(dst)?;
+    let nodata_value = header.nodata_value.unwrap_or(f64::NAN);
+    for row in grid.chunks(header.ncols.max(1)) {
+        for (col_idx, val) in row.iter().enumerate() {
+            if col_idx > 0 {
+                write!(dst, " ")?;
+            }
+            if val.is_nan() {
+                write!(dst, "{}", nodata_value)?;
+            } else {
+                totals.written += f64::from(*val);
+                write!(dst, "{}", val)?;
+            }
+        }
+        writeln!(dst)?;
+    }
+    dst.flush()?;
+    Ok(totals)
+}
+
+/// Returns the index of every pixel of `header`'s grid overlapping
+/// `h3_index`, paired with the fraction of the cell's area inside it.
+fn cell_pixels(header: &GpwAsciiHeader, h3_index: u64) -> Vec<(usize, f64)> {
+    let center_lon = header.xllcorner + header.ncols as f64 * header.dx / 2.0;
+    let cell = cell_polygon(h3_index, center_lon);
+    let Some(rect) = cell.bounding_rect() else {
+        return Vec::new();
+    };
+    // A cell containing a pole is closed along the pole twice over, so
+    // it already covers every longitude once. Any other cell may be
+    // unwrapped past the edge of a global grid, and overlap the
+    // opposite edge instead.
+    let (area, shifts): (f64, &[f64]) = if rect.width() > 360.0 {
+        (cell.unsigned_area() / 2.0, &[0.0])
+    } else {
+        (cell.unsigned_area(), &[-360.0, 0.0, 360.0])
+    };
+    if area <= 0.0 {
+        return Vec::new();
+    }
+
+    let mut pixels = Vec::new();
+    for shift in shifts {
+        let bbox = BoundingBox {
+            min_lon: rect.min().x + shift,
+            min_lat: rect.min().y,
+            max_lon: rect.max().x + shift,
+            max_lat: rect.max().y,
+        };
+        let window = bbox.window(header);
+        if window.is_empty() {
+            continue;
+        }
+        let cell = cell.translate(*shift, 0.0);
+        for row in window.rows.clone() {
+            for col in window.cols.clone() {
+                let overlap = pixel_polygon(header, row, col)
+                    .intersection(&cell)
+                    .unsigned_area();
+                if overlap > 0.0 {
+                    pixels.push((row * header.ncols + col, overlap / area));
+                }
+            }
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpwascii::GpwAsciiRows, raster::Row};
+    use hextree::h3ron::H3Cell;
+    use std::io::Cursor;
+
+    fn header(ncols: usize, nrows: usize, xll: f64, yll: f64, cellsize: f64) -> GpwAsciiHeader {
+        GpwAsciiHeader {
+            ncols,
+            nrows,
+            xllcorner: xll,
+            yllcorner: yll,
+            dx: cellsize,
+            dy: cellsize,
+            nodata_value: Some(-9999.0),
+        }
+    }
+
+    #[test]
+    fn test_cell_pixels() {
+        // Every cell of a global grid is covered exactly once, including
+        // ones on the antimeridian and at the poles.
+        let global = header(36, 18, -180.0, -90.0, 10.0);
+        for (lon, lat) in [(10.0, 45.0), (179.9, -16.0), (-179.9, 65.0), (0.0, 90.0)] {
+            let cell = H3Cell::from_coordinate(geo::coord! {x: lon, y: lat}, 2).unwrap();
+            let pixels = cell_pixels(&global, *cell);
+            let total: f64 = pixels.iter().map(|(_, weight)| weight).sum();
+            assert!((total - 1.0).abs() < 1e-9, "{} {}: {}", lon, lat, total);
+        }
+
+        // A cell partially outside a regional grid.
+        let regional = header(2, 2, 10.0, 45.0, 0.01);
+        let cell = H3Cell::from_coordinate(geo::coord! {x: 10.0, y: 45.01}, 9).unwrap();
+        let total: f64 = cell_pixels(&regional, *cell)
+            .iter()
+            .map(|(_, weight)| weight)
+            .sum();
+        assert!(total > 0.0 && total < 1.0);
+    }
+
+    #[test]
+    fn test_rasterize() {
+        let grid = header(3, 2, 10.0, 45.0, 0.01);
+        let inside = H3Cell::from_coordinate(geo::coord! {x: 10.005, y: 45.015}, 12).unwrap();
+        let outside = H3Cell::from_coordinate(geo::coord! {x: 20.0, y: 45.0}, 12).unwrap();
+        let records = vec![Ok((*inside, 4.0)), Ok((*outside, 2.0))];
+        let mut dst = Vec::new();
+        let totals = rasterize(records.into_iter(), &grid, &mut dst).unwrap();
+        assert_eq!(totals.source, 6.0);
+        assert!((totals.clipped - 2.0).abs() < 1e-9);
+        assert!(totals.relative_error() < 1e-6);
+
+        let rows = GpwAsciiRows::new(Cursor::new(dst)).unwrap();
+        assert_eq!(rows.header, grid);
+        let rows = rows.collect::<Result<Vec<Row>, _>>().unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (0, vec![Some(4.0), None, None]),
+                (1, vec![None, None, None]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::error::GpwError;
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use zip::{result::ZipError, CompressionMethod, ZipArchive};
+
+/// A raster file to read, possibly compressed or inside an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// An uncompressed file.
+    Plain(PathBuf),
+    /// A gzip-compressed file.
+    Gzip(PathBuf),
+    /// A single member of a zip archive.
+    ZipMember {
+        archive: PathBuf,
+        index: usize,
+        name: String,
+    },
+}
+
+impl Source {
+    /// Returns the sources contained in `path`.
+    ///
+    /// Zip archives expand to one source per `.asc` member, anything
+    /// else is a single source.
+    pub fn expand(path: &Path) -> Result<Vec<Source>, GpwError> {
+        match extension(path).as_deref() {
+            Some("zip") => {
+                let mut archive = ZipArchive::new(File::open(path)?)?;
+                let mut sources = Vec::new();
+                for index in 0..archive.len() {
+                    let member = archive.by_index_raw(index)?;
+                    if !member.is_dir() && member.name().to_ascii_lowercase().ends_with(".asc") {
+                        sources.push(Source::ZipMember {
+                            archive: path.to_path_buf(),
+                            index,
+                            name: member.name().to_string(),
+                        });
+                    }
+                }
+                Ok(sources)
+            }
+            Some("gz") => Ok(vec![Source::Gzip(path.to_path_buf())]),
+            _ => Ok(vec![Source::Plain(path.to_path_buf())]),
+        }
+    }
+
+    /// Returns the uncompressed file name of this source, without any
+    /// leading directories.
+    pub fn file_name(&self) -> String {
+        match self {
+            Source::Plain(path) => path_file_name(path),
+            Source::Gzip(path) => path_file_name(&path.with_extension("")),
+            Source::ZipMember { name, .. } => path_file_name(Path::new(name)),
+        }
+    }
+
+    /// Opens a streaming reader over the uncompressed contents.
+    pub fn open(&self) -> Result<Box<dyn BufRead + Send>, GpwError> {
+        Ok(Box::new(BufReader::new(self.open_unbuffered()?)))
+    }
+
+    /// Opens a streaming reader like [`Source::open`], along with the
+    /// CRC-32 of the uncompressed contents read from it so far.
+    pub fn open_checksummed(&self) -> Result<(Box<dyn BufRead + Send>, Checksum), GpwError> {
+        let checksum = Checksum::default();
+        let rdr = checksum.reader(self.open_unbuffered()?);
+        Ok((Box::new(BufReader::new(rdr)), checksum))
+    }
+
+    fn open_unbuffered(&self) -> Result<Box<dyn Read + Send>, GpwError> {
+        match self {
+            Source::Plain(path) => Ok(Box::new(File::open(path)?)),
+            Source::Gzip(path) => Ok(Box::new(MultiGzDecoder::new(File::open(path)?))),
+            Source::ZipMember { archive, index, .. } => {
+                let (method, data_start, compressed_size) = {
+                    let mut zip = ZipArchive::new(File::open(archive)?)?;
+                    let member = zip.by_index(*index)?;
+                    (
+                        member.compression(),
+                        member.data_start(),
+                        member.compressed_size(),
+                    )
+                };
+                // Read the member's data directly from its own file
+                // handle so the reader doesn't borrow the archive.
+                let mut file = File::open(archive)?;
+                file.seek(SeekFrom::Start(data_start))?;
+                let data = file.take(compressed_size);
+                match method {
+                    CompressionMethod::Stored => Ok(Box::new(data)),
+                    CompressionMethod::Deflated => Ok(Box::new(DeflateDecoder::new(data))),
+                    _ => Err(ZipError::UnsupportedArchive("unsupported compression method").into()),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Plain(path) | Source::Gzip(path) => write!(f, "{}", path.display()),
+            Source::ZipMember { archive, name, .. } => {
+                write!(f, "{}!{}", archive.display(), name)
+            }
+        }
+    }
+}
+
+/// A running CRC-32 of the bytes read through its
+/// [`ChecksumReader`]s.
+#[derive(Debug, Clone, Default)]
+pub struct Checksum(Arc<Mutex<crc32fast::Hasher>>);
+
+impl Checksum {
+    /// Returns the CRC-32 of the bytes read so far.
+    pub fn value(&self) -> u32 {
+        self.0.lock().expect("poisoned").clone().finalize()
+    }
+
+    /// Wraps `rdr` so bytes read from it are added to this checksum.
+    pub fn reader<R: Read>(&self, rdr: R) -> ChecksumReader<R> {
+        ChecksumReader {
+            rdr,
+            checksum: self.clone(),
+        }
+    }
+}
+
+/// A reader adding the bytes read to a [`Checksum`].
+pub struct ChecksumReader<R> {
+    rdr: R,
+    checksum: Checksum,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.rdr.read(buf)?;
+        self.checksum
+            .0
+            .lock()
+            .expect("poisoned")
+            .update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+fn path_file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpwascii::GpwAsciiRows, raster::Row, test_util::TestDir};
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    #[test]
+    fn test_gzip_source() {
+        let dir = TestDir::new("gzip_source");
+        let path = dir.join("gpwgen_test_gzip_source.asc.gz");
+        // Two gzip members, as written by concatenating gzip files.
+        let mut file = File::create(&path).unwrap();
+        for member in [&b"ncols 1\n"[..], b"nrows 1\n"] {
+            let mut enc = GzEncoder::new(&mut file, Compression::default());
+            enc.write_all(member).unwrap();
+            enc.finish().unwrap();
+        }
+
+        let sources = Source::expand(&path).unwrap();
+        assert_eq!(sources, vec![Source::Gzip(path.clone())]);
+        assert_eq!(sources[0].file_name(), "gpwgen_test_gzip_source.asc");
+        let mut contents = String::new();
+        sources[0]
+            .open()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "ncols 1\nnrows 1\n");
+
+        // The checksum covers every member.
+        let (mut rdr, checksum) = sources[0].open_checksummed().unwrap();
+        io::copy(&mut rdr, &mut io::sink()).unwrap();
+        assert_eq!(checksum.value(), crc32fast::hash(contents.as_bytes()));
+    }
+
+    #[test]
+    fn test_zip_source() {
+        let dir = TestDir::new("zip_source");
+        let path = dir.join("rasters.zip");
+        let raster = |val: u32| {
+            format!(
+                "ncols 2\nnrows 1\nxllcorner 0\nyllcorner 0\ncellsize 1\nNODATA_value -9999\n{} -9999\n",
+                val
+            )
+        };
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("data/stored.asc", stored).unwrap();
+        zip.write_all(raster(1).as_bytes()).unwrap();
+        zip.start_file("README.txt", deflated).unwrap();
+        zip.write_all(b"not a raster").unwrap();
+        zip.start_file("data/deflated.ASC", deflated).unwrap();
+        zip.write_all(raster(2).as_bytes()).unwrap();
+        zip.finish().unwrap();
+
+        let sources = Source::expand(&path).unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                Source::ZipMember {
+                    archive: path.clone(),
+                    index: 0,
+                    name: "data/stored.asc".to_string(),
+                },
+                Source::ZipMember {
+                    archive: path.clone(),
+                    index: 2,
+                    name: "data/deflated.ASC".to_string(),
+                },
+            ]
+        );
+        assert_eq!(sources[0].file_name(), "stored.asc");
+        assert_eq!(
+            sources[1].to_string(),
+            format!("{}!data/deflated.ASC", path.display())
+        );
+
+        for (source, val) in sources.iter().zip([1, 2]) {
+            let (rdr, checksum) = source.open_checksummed().unwrap();
+            let rows = GpwAsciiRows::new(rdr).unwrap();
+            assert_eq!(rows.header.ncols, 2);
+            let rows = rows.collect::<Result<Vec<Row>, _>>().unwrap();
+            assert_eq!(rows, vec![(0, vec![Some(val as f32), None])]);
+            assert_eq!(checksum.value(), crc32fast::hash(raster(val).as_bytes()));
+        }
+    }
+}
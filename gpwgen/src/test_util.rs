@@ -0,0 +1,67 @@
+use crate::{accumulate::CellAccumulator, TOOL};
+use gpwformat::h3tess::{H3TessHeader, H3TessReader, H3TessWriter};
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+/// A directory for one test's files, unique to the test process and
+/// removed when dropped, so concurrent `cargo test` runs don't share
+/// files.
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("gpwgen_test_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// Returns the path of `file_name` in this directory.
+    pub(crate) fn join(&self, file_name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(file_name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An in-memory h3tess file, written from [`CellAccumulator`]s which
+/// spill to their own [`TestDir`].
+pub(crate) struct H3TessFixture {
+    dir: TestDir,
+    pub(crate) dst: H3TessWriter<Cursor<Vec<u8>>>,
+}
+
+impl H3TessFixture {
+    pub(crate) fn new(name: &str, resolution: u8) -> Self {
+        let header = H3TessHeader::new(TOOL, resolution, Vec::new());
+        Self {
+            dir: TestDir::new(name),
+            dst: H3TessWriter::new(Cursor::new(Vec::new()), header).unwrap(),
+        }
+    }
+
+    /// Returns an accumulator holding at most `max_cells` in memory.
+    pub(crate) fn cells(&self, max_cells: usize) -> CellAccumulator {
+        CellAccumulator::new(self.dir.join("cells")).with_max_cells(max_cells)
+    }
+
+    /// Returns the bytes of the finished file.
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.dst.finish().unwrap().into_inner()
+    }
+
+    /// Returns the records of the finished file.
+    pub(crate) fn records(self) -> Vec<(u64, f32)> {
+        H3TessReader::new(Cursor::new(self.finish()))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+}
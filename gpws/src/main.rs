@@ -2,8 +2,8 @@
 
 mod options;
 use anyhow::Result;
-use byteorder::{LittleEndian as LE, ReadBytesExt};
 use clap::Parser;
+use gpwformat::h3tess::{H3TessReader, RECORD_SIZE};
 use hextree::{h3ron::H3Cell, HexTreeMap};
 use hyper::{
     body::Body,
@@ -14,7 +14,7 @@ use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use std::{
     convert::TryFrom,
     fs::File,
-    io::{BufReader, ErrorKind},
+    io::BufReader,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
@@ -33,7 +33,7 @@ static GLOBAL: Jemalloc = Jemalloc;
 async fn main() -> Result<()> {
     let args = options::Cli::parse();
     let f = File::open(args.path)?;
-    let map: HexTreeMap<f32> = deserialize_hexmap(f)?;
+    let map: HexTreeMap<f32> = deserialize_hexmap(f, args.legacy)?;
     let map = Arc::new(map);
 
     let make_service = make_service_fn(move |_| {
@@ -74,12 +74,20 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn deserialize_hexmap(src_file: File) -> Result<HexTreeMap<f32>> {
+fn deserialize_hexmap(src_file: File, legacy: bool) -> Result<HexTreeMap<f32>> {
     let file_size = src_file.metadata()?.len();
+    let rdr = BufReader::new(src_file);
+    let rdr = if legacy {
+        H3TessReader::legacy(rdr)
+    } else {
+        H3TessReader::new(rdr)?
+    };
+    let idx_val_pairs_total = match rdr.header() {
+        Some(header) => header.record_count,
+        None => file_size / RECORD_SIZE,
+    };
     let mut map = HexTreeMap::new();
     let mut ret_err: Option<anyhow::Error> = None;
-    let idx_val_pairs_total =
-        file_size / (std::mem::size_of::<u64>() + std::mem::size_of::<f32>()) as u64;
     let idx_val_pairs_processed = AtomicU64::new(0);
 
     {
@@ -90,31 +98,21 @@ fn deserialize_hexmap(src_file: File) -> Result<HexTreeMap<f32>> {
 
     thread::scope(|s| {
         s.spawn(|| {
-            let mut rdr = BufReader::new(src_file);
-            loop {
-                match (rdr.read_u64::<LE>(), rdr.read_f32::<LE>()) {
-                    (Ok(h3_index), Ok(val)) => {
+            for record in rdr {
+                match record {
+                    Ok((h3_index, val)) => {
                         let cell = H3Cell::try_from(h3_index)
                             .expect("serialized hexmap should only contain valid indices");
                         map.insert(cell, val);
                         idx_val_pairs_processed.fetch_add(1, Ordering::Relaxed);
                     }
-                    (Err(e), _) if e.kind() == ErrorKind::UnexpectedEof => {
-                        hexmap_complete.store(true, Ordering::Relaxed);
-                        break;
-                    }
-                    (Err(e), _) => {
-                        ret_err = Some(e.into());
-                        hexmap_complete.store(true, Ordering::Relaxed);
-                        break;
-                    }
-                    (_, Err(e)) => {
+                    Err(e) => {
                         ret_err = Some(e.into());
-                        hexmap_complete.store(true, Ordering::Relaxed);
                         break;
                     }
                 };
             }
+            hexmap_complete.store(true, Ordering::Relaxed);
         });
 
         s.spawn(|| {
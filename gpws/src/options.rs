@@ -6,4 +6,7 @@ use clap::Parser;
 pub struct Cli {
     /// Path to serialized H3 (cell, population) pairs.
     pub path: std::path::PathBuf,
+    /// Read a file written before h3tess files had a header.
+    #[arg(long)]
+    pub legacy: bool,
 }